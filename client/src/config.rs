@@ -0,0 +1,51 @@
+use protocol::compress::Compression;
+use protocol::conf::Conf;
+use protocol::framing::{FEATURE_BATCH, FEATURE_FRAGMENT, FEATURE_MTU, FEATURE_REKEY};
+use protocol::mtu::MtuSetting;
+use protocol::noise::params::{DEFAULT_SUITE, Suite};
+use std::io;
+use std::path::Path;
+
+pub const BASE_DIR: &str = "/etc/blackwire-client";
+const CONFIG_FILE: &str = "client.conf";
+
+pub struct ClientConfig {
+    pub server: String,
+    pub port: u32,
+    /// Namespace the TAP device is created in.
+    pub netns: Option<String>,
+    /// Namespace the connection to the server is made from.
+    pub uplink_netns: Option<String>,
+    pub compression: Compression,
+    /// TAP MTU, or `auto` to size it from the path MTU.
+    pub mtu: MtuSetting,
+    /// Noise cipher suite to use.
+    pub cipher: Suite,
+}
+
+impl ClientConfig {
+    pub fn load(base: impl AsRef<Path>) -> io::Result<Self> {
+        let conf = Conf::load(base.as_ref().join(CONFIG_FILE))?;
+
+        Ok(Self {
+            server: conf
+                .get_string("server")
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: conf.get_or("port", 9000)?,
+            netns: conf.get_string("netns"),
+            uplink_netns: conf.get_string("uplink_netns"),
+            compression: conf.get_or("compression", Compression::None)?,
+            mtu: conf.get_or("mtu", MtuSetting::Auto)?,
+            cipher: conf.get_or("cipher", DEFAULT_SUITE)?,
+        })
+    }
+
+    /// Optional protocol features to offer the server.
+    pub fn features(&self) -> u32 {
+        let mtu = match self.mtu {
+            MtuSetting::Auto => FEATURE_MTU,
+            MtuSetting::Fixed(_) => 0,
+        };
+        FEATURE_BATCH | FEATURE_FRAGMENT | FEATURE_REKEY | mtu | self.compression.feature()
+    }
+}
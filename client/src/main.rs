@@ -1,85 +1,356 @@
-use protocol::auth::Auth;
-use protocol::framing::{ControlType, OpCode, classify_frame, frame_ethernet, parse_control_frame};
-use protocol::noise::client::client_handshake;
-use protocol::noise::util::{
-    decrypt, recv_ciphertext, safe_decrypt, safe_encrypt, send_ciphertext,
+mod config;
+
+use config::{BASE_DIR, ClientConfig};
+use crossbeam_channel::Sender;
+use protocol::auth::credential::load_credential;
+use protocol::auth::keyfile::{read_passphrase, read_private_key, write_private_key};
+use protocol::auth::{Auth, PRIV_FILE, add_allowed, check_keys_setup, replace_allowed_key};
+use protocol::compress::{Compression, decompress_message};
+use protocol::fragment::Reassembler;
+use protocol::framing::{
+    ControlType, DisconnectReason, OpCode, SUPPORTED_FEATURES, classify_frame, frame_handshake,
+    parse_control_frame, parse_disconnect, parse_ethernet_batch, parse_handshake, parse_mtu,
+    parse_server_key,
 };
+use protocol::mtu::{DEFAULT_TUNNEL_MTU, MAX_TUNNEL_MTU, MtuSetting, max_frame_len};
+use protocol::noise::client::client_handshake;
+use protocol::noise::enrol::client_enrol;
+use protocol::noise::session::Session;
+use protocol::noise::util::{MAX_BATCH, decrypt, recv_ciphertext};
 use protocol::ok_or_continue;
+use protocol::signals::{Signal, Signals};
 use snow::TransportState;
-use std::io;
+use std::io::{self, BufReader};
 use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use tap::Tap;
 
+const MTU_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type CurrentSession = Arc<Mutex<Option<Arc<Session>>>>;
+
+/// What the signal thread wants the connection loop in `main` to do next.
+#[derive(Default)]
+struct Control {
+    stopping: AtomicBool,
+    reload: AtomicBool,
+}
+
 pub fn main() -> io::Result<()> {
-    let addr = "127.0.0.1";
-    let port = 9000;
+    let config = ClientConfig::load(BASE_DIR)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(cmd) = args.first() {
+        return match cmd.as_str() {
+            "enrol" => enrol(&config, &args[1..]),
+            "passphrase" => passphrase(),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown command `{}`", cmd),
+            )),
+        };
+    }
+
+    // Before any threads exist, so that only the signal thread sees these.
+    let signals = Signals::block()?;
 
-    let auth: Auth = Auth::new("/etc/blackwire-client")?;
+    let mut config = config;
+    let mut auth: Auth = Auth::new(BASE_DIR)?;
 
+    // The TAP outlives any one session; its threads always use the current one.
+    let current: CurrentSession = Arc::new(Mutex::new(None));
+    let mut shared_tap: Option<Arc<Tap>> = None;
+
+    let control = Arc::new(Control::default());
+    let (wake_tx, wake_rx) = crossbeam_channel::bounded::<()>(1);
+    {
+        let current = Arc::clone(&current);
+        let control = Arc::clone(&control);
+        thread::spawn(move || handle_signals(signals, current, control, wake_tx));
+    }
+
+    while !control.stopping.load(Ordering::SeqCst) {
+        if control.reload.swap(false, Ordering::SeqCst) {
+            println!("Reloading config and keys");
+            match ClientConfig::load(BASE_DIR).and_then(|c| Ok((c, Auth::new(BASE_DIR)?))) {
+                Ok((new_config, new_auth)) => {
+                    config = new_config;
+                    auth = new_auth;
+                }
+                Err(e) => eprintln!("Keeping the old config and keys: {}", e),
+            }
+        }
+
+        match connect_session(&config, &auth) {
+            Ok((mut stream, session, mac)) => {
+                let tap = match &shared_tap {
+                    Some(tap) => {
+                        if tap.get_mac()? != mac {
+                            tap.set_mac(mac)?;
+                        }
+                        Arc::clone(tap)
+                    }
+                    None => {
+                        let (tap, mtu) = setup_tap(&config, mac, &session)?;
+                        let tap = Arc::new(tap);
+                        start_threads(&tap, &current, &config, mtu);
+                        shared_tap = Some(Arc::clone(&tap));
+                        tap
+                    }
+                };
+
+                {
+                    // Checked under the lock, so the signal thread either sees this session or
+                    // we see that it wants us to stop.
+                    let mut slot = current.lock().unwrap();
+                    if control.stopping.load(Ordering::SeqCst) {
+                        session.disconnect(DisconnectReason::Shutdown, "Client shutting down");
+                        break;
+                    }
+                    *slot = Some(Arc::clone(&session));
+                }
+                read_from_stream(&tap, &mut stream, &session, &control);
+                *current.lock().unwrap() = None;
+                session.close();
+            }
+            Err(e) => eprintln!("Failed to connect: {}", e),
+        }
+
+        if control.stopping.load(Ordering::SeqCst) || control.reload.load(Ordering::SeqCst) {
+            continue;
+        }
+        println!("Reconnecting in {}s", RECONNECT_DELAY.as_secs());
+        let _ = wake_rx.recv_timeout(RECONNECT_DELAY);
+    }
+
+    println!("Stopped");
+    Ok(())
+}
+
+/// Stopping and reloading both end the current session; the loop in `main` then either returns
+/// or reconnects with the new config.
+fn handle_signals(
+    signals: Signals,
+    current: CurrentSession,
+    control: Arc<Control>,
+    wake: Sender<()>,
+) {
+    loop {
+        let (reason, message) = match signals.wait() {
+            Ok(Signal::Stop) => {
+                println!("Shutting down");
+                control.stopping.store(true, Ordering::SeqCst);
+                (DisconnectReason::Shutdown, "Client shutting down")
+            }
+            Ok(Signal::Reload) => {
+                control.reload.store(true, Ordering::SeqCst);
+                (DisconnectReason::Other, "Client reloading")
+            }
+            Err(e) => {
+                eprintln!("Failed to wait for signals: {}", e);
+                return;
+            }
+        };
+
+        if let Some(session) = current.lock().unwrap().take() {
+            session.disconnect(reason, message);
+        }
+        let _ = wake.try_send(());
+    }
+}
+
+/// Connects and handshakes, returning the new session and the MAC the server assigned us.
+fn connect_session(
+    config: &ClientConfig,
+    auth: &Auth,
+) -> io::Result<(TcpStream, Arc<Session>, [u8; 6])> {
     let server_static = auth
         .get_pub("server".to_string())
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No server public key found."))?;
+        .ok_or_else(|| io::Error::other("No server public key found."))?;
 
     // Make a connection to the server.
-    let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).expect("Failed to connect");
+    let mut stream = connect(config)?;
 
     // Perform noise handshake
-    let mut transport = client_handshake(&mut stream, &auth.keypair, server_static)?;
+    let psk = auth.get_psk("server");
+    let credential = load_credential(BASE_DIR)?;
+    let mut transport = client_handshake(
+        &mut stream,
+        &auth.keypair,
+        server_static,
+        psk,
+        credential.as_deref(),
+        config.cipher,
+    )?;
 
     println!("Noise handshake complete");
 
     // Perform protocol handshake
     let mac = blackwire_handshake(&mut stream, &mut transport)?;
 
-    // Set up TAP device
-    let tap = Tap::new("bwc0")?;
-    tap.set_mtu(1400)?;
+    let session = Arc::new(Session::new(transport, stream.try_clone()?)?);
+
+    // Offer optional features. Older servers never answer, so we just carry on without them.
+    session.send(&frame_handshake(config.features()))?;
+
+    Ok((stream, session, mac))
+}
+
+fn setup_tap(config: &ClientConfig, mac: [u8; 6], session: &Session) -> io::Result<(Tap, i32)> {
+    let tap = match &config.netns {
+        Some(netns) => Tap::new_in_netns("bwc0", netns)?,
+        None => Tap::new("bwc0")?,
+    };
+    let mtu = match config.mtu {
+        MtuSetting::Fixed(mtu) => mtu,
+        MtuSetting::Auto => session.tunnel_mtu().unwrap_or(DEFAULT_TUNNEL_MTU),
+    };
+    println!("Tunnel MTU is {}", mtu);
+    tap.set_mtu(mtu)?;
     tap.set_mac(mac)?;
     tap.up()?;
+    Ok((tap, mtu))
+}
 
-    let shared_tap = Arc::new(tap);
-    let shared_transport = Arc::new(Mutex::new(transport));
-
-    // Set up threads
-    let read_tap = Arc::clone(&shared_tap);
-    let read_transport = Arc::clone(&shared_transport);
-    let mut read_stream = stream.try_clone()?;
+fn start_threads(tap: &Arc<Tap>, current: &CurrentSession, config: &ClientConfig, mtu: i32) {
+    let read_tap = Arc::clone(tap);
+    let read_current = Arc::clone(current);
+    // With `auto` the MTU can grow later, so leave room for the largest it can become.
+    let max_mtu = match config.mtu {
+        MtuSetting::Fixed(mtu) => mtu,
+        MtuSetting::Auto => MAX_TUNNEL_MTU,
+    };
     thread::spawn(move || {
-        read_from_tap(read_tap, &mut read_stream, read_transport);
+        read_from_tap(read_tap, read_current, max_mtu);
     });
 
-    let stream_tap = Arc::clone(&shared_tap);
-    let stream_transport = Arc::clone(&shared_transport);
-    let mut recv_stream = stream.try_clone()?;
-    thread::spawn(move || {
-        read_from_stream(stream_tap, &mut recv_stream, stream_transport);
-    });
+    if config.mtu == MtuSetting::Auto {
+        let mtu_tap = Arc::clone(tap);
+        let mtu_current = Arc::clone(current);
+        thread::spawn(move || {
+            watch_mtu(mtu_tap, mtu_current, mtu);
+        });
+    }
+}
+
+fn connect(config: &ClientConfig) -> io::Result<TcpStream> {
+    let addr = format!("{}:{}", config.server, config.port);
+
+    // The socket stays in the namespace it was created in.
+    #[cfg(target_os = "linux")]
+    return tap::netns::in_netns(config.uplink_netns.as_deref(), || TcpStream::connect(&addr));
+
+    #[allow(unreachable_code)]
+    TcpStream::connect(&addr)
+}
+
+/// `client enrol <token>`: joins the server with a one-time token and pins its key.
+fn enrol(config: &ClientConfig, args: &[String]) -> io::Result<()> {
+    let token = args.first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Usage: client enrol <token>")
+    })?;
+
+    let auth = Auth::new(BASE_DIR)?;
+    let mut stream = connect(config)?;
+
+    let (server_static, name) = client_enrol(&mut stream, &auth.keypair, config.cipher, token)?;
+
+    // Trust on first use: a key we already pinned has to match.
+    add_allowed(BASE_DIR, "server", &server_static)?;
+
+    println!(
+        "Enrolled as `{}`, pinned server key {}",
+        name,
+        hex::encode(&server_static)
+    );
+    Ok(())
+}
+
+/// `client passphrase`: encrypts the private key with a new passphrase, or stores it in the clear
+/// again if none is given.
+fn passphrase() -> io::Result<()> {
+    check_keys_setup(BASE_DIR)?;
+    let path = Path::new(BASE_DIR).join(PRIV_FILE);
+    let key = read_private_key(&path)?;
+
+    let new = read_passphrase("New passphrase (empty for none): ")?;
+    if read_passphrase("Repeat it: ")? != new {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Passphrases don't match",
+        ));
+    }
 
-    loop {}
+    if new.is_empty() {
+        write_private_key(&path, &key, None)?;
+        println!("Private key is no longer encrypted");
+    } else {
+        write_private_key(&path, &key, Some(&new))?;
+        println!("Private key encrypted");
+    }
+    Ok(())
+}
+
+/// Stores the key the server rotated to. It came over a session authenticated with the key we
+/// had pinned, so it can be trusted just as much. It is used from the next connection on.
+fn pin_server_key(key: &[u8], control: &Control) -> io::Result<()> {
+    replace_allowed_key(BASE_DIR, "server", key)?;
+    println!("Server rotated its key, pinned {}", hex::encode(key));
+    control.reload.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Follows path MTU changes on our side and whatever the server advertised for its side.
+fn watch_mtu(tap: Arc<Tap>, current: CurrentSession, mut mtu_now: i32) {
+    loop {
+        thread::sleep(MTU_CHECK_INTERVAL);
+
+        let Some(session) = current.lock().unwrap().clone() else {
+            continue;
+        };
+
+        let mut mtu = session.tunnel_mtu().unwrap_or(DEFAULT_TUNNEL_MTU);
+        if let Some(peer) = session.peer_mtu() {
+            mtu = mtu.min(peer);
+        }
+
+        if mtu != mtu_now {
+            match tap.set_mtu(mtu) {
+                Ok(()) => {
+                    println!("Tunnel MTU changed from {} to {}", mtu_now, mtu);
+                    mtu_now = mtu;
+                }
+                Err(e) => eprintln!("Failed to update tunnel MTU: {}", e),
+            }
+        }
+    }
 }
 
 fn blackwire_handshake(
     stream: &mut TcpStream,
-    mut transport: &mut TransportState,
+    transport: &mut TransportState,
 ) -> io::Result<[u8; 6]> {
     let ciphertext = recv_ciphertext(stream)?;
-    let msg = decrypt(&mut transport, &ciphertext)?;
+    let msg = decrypt(transport, &ciphertext)?;
 
     let opcode = classify_frame(&msg)?;
     if opcode != OpCode::Control {
-        return Err(io::Error::new(io::ErrorKind::Other, "Incorrect handshake"));
+        return Err(io::Error::other("Incorrect handshake"));
     }
 
     let (control_type, mac) = parse_control_frame(&msg)?;
     if control_type != ControlType::AssignMac {
-        return Err(io::Error::new(io::ErrorKind::Other, "Incorrect handshake"));
+        return Err(io::Error::other("Incorrect handshake"));
     }
 
     // Verify that the payload is 6 bytes (it's a MAC address)
     if mac.len() != 6 {
-        return Err(io::Error::new(io::ErrorKind::Other, "Incorrect handshake"));
+        return Err(io::Error::other("Incorrect handshake"));
     }
 
     println!("Received MAC address! {:02x?}", mac);
@@ -87,35 +358,73 @@ fn blackwire_handshake(
     Ok(mac_arr)
 }
 
-fn read_from_tap(tap: Arc<Tap>, stream: &mut TcpStream, transport: Arc<Mutex<TransportState>>) {
+fn read_from_tap(tap: Arc<Tap>, current: CurrentSession, max_mtu: i32) {
+    let mut bufs = vec![vec![0u8; max_frame_len(max_mtu)]; MAX_BATCH];
+    let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+    let mut lens = [0usize; MAX_BATCH];
+
     loop {
-        // Read ethernet frame from TAP.
-        let mut buf = [0u8; 2000];
-        let size = ok_or_continue!(tap.read(&mut buf));
-        println!("Got an ethernet frame from the TAP!");
+        // Read every ethernet frame queued on the TAP.
+        let count = ok_or_continue!(tap.read_batch(&mut slices, &mut lens));
+        println!("Got {} ethernet frame(s) from the TAP!", count);
 
-        // Frame ethernet frame.
-        let frame = frame_ethernet(&buf[..size]);
+        // Frame ethernet frames.
+        let frames: Vec<&[u8]> = slices
+            .iter()
+            .zip(&lens)
+            .take(count)
+            .map(|(buf, &n)| &buf[..n])
+            .collect();
 
-        // Encrypt and send ethernet frame.
-        let ciphertext = ok_or_continue!(safe_encrypt(&transport, &frame));
-        ok_or_continue!(send_ciphertext(stream, &ciphertext));
+        // Encrypt and send ethernet frames. Between sessions they are dropped, like on a
+        // cable that has been pulled out.
+        let Some(session) = current.lock().unwrap().clone() else {
+            continue;
+        };
+        ok_or_continue!(session.send_frames(&frames));
     }
 }
 
-fn read_from_stream(tap: Arc<Tap>, stream: &mut TcpStream, transport: Arc<Mutex<TransportState>>) {
+/// Handles everything the server sends until the session ends.
+fn read_from_stream(tap: &Tap, stream: &mut TcpStream, session: &Session, control: &Control) {
+    let mut reader = BufReader::new(stream);
+    let mut reassembler = Reassembler::new();
     loop {
-        // Read and decrypt message from TcpStream
-        let ciphertext = ok_or_continue!(recv_ciphertext(stream));
-        let data = ok_or_continue!(safe_decrypt(&transport, &ciphertext));
+        // Read and decrypt message from TcpStream. Either failing means the connection is done.
+        let data = match recv_ciphertext(&mut reader).and_then(|c| session.decrypt(&c)) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Lost connection to server: {}", e);
+                return;
+            }
+        };
+        let Some(data) = ok_or_continue!(reassembler.push(data)) else {
+            continue;
+        };
+        let compression = Compression::from_features(session.features());
+        let data = ok_or_continue!(decompress_message(data, compression));
         // Classify message
         match ok_or_continue!(classify_frame(&data)) {
             OpCode::Control => {
                 let (ctrl_type, payload) = ok_or_continue!(parse_control_frame(&data));
                 match ctrl_type {
-                    ControlType::Handshake => {}
+                    ControlType::Handshake => {
+                        // The server's answer to our offer.
+                        let features = ok_or_continue!(parse_handshake(payload));
+                        session.set_features(features & SUPPORTED_FEATURES);
+                    }
                     ControlType::AssignMac => {}
                     ControlType::Pong => {}
+                    ControlType::Mtu => {
+                        session.set_peer_mtu(ok_or_continue!(parse_mtu(payload)));
+                    }
+                    ControlType::Rekey => {
+                        println!("Server rekeyed ({} so far)", session.rekeys_received());
+                    }
+                    ControlType::ServerKey => {
+                        let key = ok_or_continue!(parse_server_key(payload));
+                        ok_or_continue!(pin_server_key(key, control));
+                    }
                 }
             }
 
@@ -123,9 +432,19 @@ fn read_from_stream(tap: Arc<Tap>, stream: &mut TcpStream, transport: Arc<Mutex<
                 // Send this to TAP
                 println!("Received ethernet from server, writing it out TAP!");
                 let ethernet = &data[1..];
-                ok_or_continue!(tap.write(&ethernet));
+                ok_or_continue!(tap.write(ethernet));
+            }
+            OpCode::EthernetBatch => {
+                let frames = ok_or_continue!(parse_ethernet_batch(&data));
+                ok_or_continue!(tap.write_batch(&frames));
             }
             OpCode::IP => {}
+            OpCode::Disconnect => {
+                let (reason, message) = ok_or_continue!(parse_disconnect(&data));
+                println!("Server disconnected us ({:?}): {}", reason, message);
+                return;
+            }
+            OpCode::Compressed | OpCode::Fragment => {}
         }
     }
 }
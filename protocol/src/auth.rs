@@ -1,23 +1,62 @@
-use snow::{Builder, Keypair};
-use std::collections::HashMap;
-use std::fs;
-use std::io;
+pub mod credential;
+pub mod index;
+pub mod keyfile;
+pub mod peer;
+
+use crate::conf::Conf;
+use crate::noise::params::{DEFAULT_SUITE, Pattern};
+use credential::Credential;
+use ed25519_dalek::VerifyingKey;
+use index::{PeerIndex, PublicKey};
+use keyfile::{StaticKeypair, is_encrypted, passphrase, read_private_key, write_private_key};
+use peer::{Peer, now};
+use snow::Builder;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::Duration;
 
-const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
-const PRIV_FILE: &str = "private.key";
+pub const PRIV_FILE: &str = "private.key";
 const PUB_FILE: &str = "public.key";
+/// The key we rotated away from, kept until clients have had time to learn the new one.
+const PREVIOUS_PRIV_FILE: &str = "previous.key";
+const PREVIOUS_FILE: &str = "previous";
 const ALLOWED_DIR: &str = "allowed/";
+pub const REVOKED_FILE: &str = "revoked";
+pub const TRUSTED_CAS_FILE: &str = "trusted_cas";
+const PSK_EXT: &str = "psk";
+const PSK_LEN: usize = 32;
 
 pub type SharedAuth = Arc<Mutex<Auth>>;
 
 pub struct Auth {
-    pub keypair: Keypair,
-    pub allowed: HashMap<String, Vec<u8>>,
+    /// Shared rather than copied, so the private key is only in memory once.
+    pub keypair: Arc<StaticKeypair>,
+    /// The key `keypair` replaced, while clients may still use it.
+    pub previous: Option<PreviousKey>,
+    /// Everyone in `allowed/`, by name and by key.
+    pub peers: PeerIndex,
+    /// Keys listed in `revoked`, which are refused even if a peer file still has them.
+    pub revoked: HashSet<PublicKey>,
+    /// Ed25519 keys listed in `trusted_cas`, whose credentials let clients in without a peer file.
+    pub trusted_cas: Vec<VerifyingKey>,
     base: PathBuf,
-    last_loaded: SystemTime,
+}
+
+pub struct PreviousKey {
+    pub keypair: Arc<StaticKeypair>,
+    /// Unix time from which it is no longer accepted.
+    pub until: u64,
+}
+
+impl PreviousKey {
+    /// How much longer it is accepted, if at all.
+    pub fn expires_in(&self) -> Option<Duration> {
+        let now = now();
+        (now < self.until).then(|| Duration::from_secs(self.until - now))
+    }
 }
 
 impl Auth {
@@ -31,56 +70,136 @@ impl Auth {
     pub fn load(base: impl Into<PathBuf>) -> io::Result<Self> {
         let base = base.into();
 
-        let private = read_hex(&base.join(PRIV_FILE))?;
+        let private = read_private_key(&base.join(PRIV_FILE))?;
         let public = read_hex(&base.join(PUB_FILE))?;
 
-        let kp = Keypair { private, public };
+        let kp = Arc::new(StaticKeypair::new(&private, public));
+        let previous = load_previous_key(&base)?;
 
         let allowed_path = base.join(ALLOWED_DIR);
 
         // Read in all the clients.
-        let (map, mtime) = load_allowed_clients_with_mtime(&allowed_path)?;
+        let peers = load_allowed_clients(&allowed_path)?;
+        let revoked = load_revoked(&base.join(REVOKED_FILE))?;
+        let trusted_cas = load_trusted_cas(&base.join(TRUSTED_CAS_FILE))?;
 
         Ok(Self {
             keypair: kp,
-            allowed: map,
+            previous,
+            peers,
+            revoked,
+            trusted_cas,
             base,
-            last_loaded: mtime,
         })
     }
 
-    pub fn reload_if_modified(&mut self) -> io::Result<()> {
-        let allowed_path = self.base.join(ALLOWED_DIR);
+    /// The directory peers are loaded from.
+    pub fn allowed_dir(&self) -> PathBuf {
+        self.base.join(ALLOWED_DIR)
+    }
+
+    pub fn revoked_file(&self) -> PathBuf {
+        self.base.join(REVOKED_FILE)
+    }
+
+    pub fn trusted_cas_file(&self) -> PathBuf {
+        self.base.join(TRUSTED_CAS_FILE)
+    }
+
+    /// Reads `allowed/`, `revoked` and `trusted_cas` again, e.g. after something in them changed.
+    pub fn reload_allowed(&mut self) -> io::Result<()> {
+        self.peers = load_allowed_clients(&self.allowed_dir())?;
+        self.revoked = load_revoked(&self.revoked_file())?;
+        self.trusted_cas = load_trusted_cas(&self.trusted_cas_file())?;
+        println!("Reloaded {} allowed peers", self.peers.len());
+        Ok(())
+    }
 
-        if !allowed_path.exists() {
-            return Ok(());
+    /// The keys clients may know us by: ours, then the one it replaced until its grace period
+    /// is over.
+    pub fn static_keys(&self) -> Vec<Arc<StaticKeypair>> {
+        let mut keys = vec![Arc::clone(&self.keypair)];
+        if let Some(previous) = &self.previous
+            && previous.expires_in().is_some()
+        {
+            keys.push(Arc::clone(&previous.keypair));
         }
+        keys
+    }
 
-        let m = fs::metadata(&allowed_path)?.modified()?;
+    /// Whether the key belongs to a peer that may connect right now.
+    pub fn is_allowed(&self, key: &[u8]) -> bool {
+        self.authenticate(key, None).is_ok()
+    }
 
-        if m > self.last_loaded {
-            let (map, mtime) = load_allowed_clients_with_mtime(&allowed_path)?;
-            self.allowed = map;
-            self.last_loaded = mtime;
-            println!("Reloaded allowed client keys");
+    /// Finds out who a key belongs to, refusing peers that can't connect right now. Keys
+    /// without a peer file can still get in with a credential from a trusted CA.
+    pub fn authenticate(&self, key: &[u8], credential: Option<&[u8]>) -> io::Result<Peer> {
+        let denied = |msg: String| io::Error::new(io::ErrorKind::PermissionDenied, msg);
+
+        let peer = match (self.peer_for(key), credential) {
+            (Some(peer), _) => Some(peer.clone()),
+            (None, Some(credential)) => Some(
+                self.peer_from_credential(key, credential)
+                    .map_err(|e| denied(format!("Invalid credential: {}", e)))?,
+            ),
+            (None, None) => None,
+        };
+
+        match &peer {
+            _ if self.revoked.contains(&PublicKey::from(key)) => Err(denied(match peer {
+                Some(peer) => format!("Peer {} has been revoked", peer),
+                None => "Revoked key".to_string(),
+            })),
+            Some(peer) if !peer.enabled => Err(denied(format!("Peer {} is disabled", peer))),
+            Some(peer) if peer.is_not_yet_valid() => {
+                Err(denied(format!("Peer {} is not valid yet", peer)))
+            }
+            Some(peer) if peer.is_expired() => Err(denied(format!("Peer {} has expired", peer))),
+            Some(peer) => Ok(peer.clone()),
+            None => Err(denied("Unauthorized client".to_string())),
         }
+    }
 
-        Ok(())
+    fn peer_from_credential(&self, key: &[u8], data: &[u8]) -> io::Result<Peer> {
+        let credential = Credential::verify(data, &self.trusted_cas)?;
+        if credential.public_key != key {
+            return Err(io::Error::other("Issued for a different key"));
+        }
+        // A peer file always wins, so its name can't be borrowed by someone else.
+        if self.peers.contains_key(&credential.name) {
+            return Err(io::Error::other(format!(
+                "`{}` already has a peer file",
+                credential.name
+            )));
+        }
+
+        let mut peer = credential.to_peer()?;
+        peer.credential = Some(data.to_vec());
+        Ok(peer)
     }
 
-    pub fn is_allowed(&self, key: &[u8]) -> bool {
-        self.allowed.values().any(|k| k.as_slice() == key)
+    pub fn peer_for(&self, key: &[u8]) -> Option<&Peer> {
+        self.peers.by_key(key)
     }
 
     pub fn get_pub(&self, key: String) -> Option<&[u8]> {
-        self.allowed.get(&key).map(|v| v.as_slice())
+        self.peers.get(&key).map(|p| p.public_key.as_slice())
+    }
+
+    pub fn get_psk(&self, name: &str) -> Option<&[u8]> {
+        self.peers.get(name).and_then(|p| p.psk.as_deref())
+    }
+
+    /// The pre-shared key configured for whichever peer owns this public key.
+    pub fn psk_for(&self, key: &[u8]) -> Option<&[u8]> {
+        self.peer_for(key).and_then(|p| p.psk.as_deref())
     }
 }
 
-fn load_allowed_clients_with_mtime(
-    dir: &Path,
-) -> io::Result<(HashMap<String, Vec<u8>>, SystemTime)> {
-    let mut map = HashMap::new();
+fn load_allowed_clients(dir: &Path) -> io::Result<PeerIndex> {
+    let mut peers = HashMap::new();
+    let mut psks = HashMap::new();
 
     if dir.exists() {
         for entry in fs::read_dir(dir)? {
@@ -89,15 +208,237 @@ fn load_allowed_clients_with_mtime(
             if path.is_file() {
                 let filename = entry.file_name().into_string().unwrap_or_default();
 
-                if let Ok(data) = read_hex(&path) {
-                    map.insert(filename, data);
+                if path.extension().is_some_and(|ext| ext == PSK_EXT) {
+                    let name = filename.trim_end_matches(".psk").to_string();
+                    match read_hex(&path) {
+                        Ok(psk) if psk.len() == PSK_LEN => {
+                            psks.insert(name, psk);
+                        }
+                        _ => eprintln!("Ignoring invalid pre-shared key {}", path.display()),
+                    }
+                } else {
+                    match fs::read_to_string(&path).and_then(|text| Peer::parse(&filename, &text)) {
+                        Ok(peer) => {
+                            peers.insert(filename, peer);
+                        }
+                        Err(e) => eprintln!("Ignoring peer file {}: {}", path.display(), e),
+                    }
                 }
             }
         }
     }
 
-    let mtime = fs::metadata(dir)?.modified()?;
-    return Ok((map, mtime));
+    // A `psk` in the peer file wins over a separate `.psk` file.
+    for (name, psk) in psks {
+        if let Some(peer) = peers.get_mut(&name) {
+            peer.psk.get_or_insert(psk);
+        }
+    }
+
+    // Keys of the wrong length would only fail later, in the middle of a handshake.
+    for peer in peers.values_mut() {
+        if peer.psk.as_ref().is_some_and(|psk| psk.len() != PSK_LEN) {
+            eprintln!("Ignoring invalid pre-shared key for `{}`", peer.name);
+            peer.psk = None;
+        }
+    }
+
+    for warning in peers.values().filter_map(Peer::expiry_warning) {
+        println!("{}", warning);
+    }
+
+    Ok(PeerIndex::new(peers))
+}
+
+fn load_previous_key(base: &Path) -> io::Result<Option<PreviousKey>> {
+    let conf = Conf::load(base.join(PREVIOUS_FILE))?;
+    let (Some(public), Some(until)) = (conf.get("public_key"), conf.get_parsed("until")?) else {
+        return Ok(None);
+    };
+    let public = hex::decode(public)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid previous public key"))?;
+    let private = read_private_key(&base.join(PREVIOUS_PRIV_FILE))?;
+
+    Ok(Some(PreviousKey {
+        keypair: Arc::new(StaticKeypair::new(&private, public)),
+        until,
+    }))
+}
+
+/// Replaces our static key with a new one, which is returned. The old key keeps being accepted
+/// for `grace`, and clients using it are told the new one. Everything new is written out before
+/// anything is replaced, and `private.key` is only ever swapped by a rename, so it holds a
+/// usable key however far this gets.
+pub fn rotate_static_key(base: impl AsRef<Path>, grace: Duration) -> io::Result<Vec<u8>> {
+    let base = base.as_ref();
+    if let Some(left) = load_previous_key(base)?.and_then(|p| p.expires_in()) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "The last rotation is still in its grace period for another {}",
+                peer::format_duration(left)
+            ),
+        ));
+    }
+
+    let priv_path = base.join(PRIV_FILE);
+    let passphrase = match is_encrypted(&priv_path)? {
+        true => Some(passphrase()?),
+        false => None,
+    };
+    let old_public = read_hex(&base.join(PUB_FILE))?;
+    let until = now()
+        .checked_add(grace.as_secs())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Grace period too long"))?;
+
+    let kp = generate_static_keypair();
+    let new_priv = staged(&priv_path);
+    let new_pub = staged(&base.join(PUB_FILE));
+    write_private_key(&new_priv, &kp.private, passphrase)?;
+    fs::write(&new_pub, hex::encode(&kp.public))?;
+
+    // A copy keeps the old key's permissions and, if it has one, its passphrase.
+    let prev_priv = base.join(PREVIOUS_PRIV_FILE);
+    fs::copy(&priv_path, staged(&prev_priv))?;
+    fs::rename(staged(&prev_priv), &prev_priv)?;
+    let prev = base.join(PREVIOUS_FILE);
+    fs::write(
+        staged(&prev),
+        format!(
+            "public_key = {}\nuntil = {}\n",
+            hex::encode(old_public),
+            until
+        ),
+    )?;
+    fs::rename(staged(&prev), &prev)?;
+
+    fs::rename(new_priv, &priv_path)?;
+    fs::rename(new_pub, base.join(PUB_FILE))?;
+
+    Ok(kp.public.clone())
+}
+
+// Where a file is written before it is renamed into place.
+fn staged(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".new");
+    path.with_file_name(name)
+}
+
+/// Key lists like `revoked` have one hex key per line. Anything after a `#` is a comment.
+fn load_key_list(path: &Path) -> io::Result<HashSet<Vec<u8>>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut revoked = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match hex::decode(line) {
+            Ok(key) => {
+                revoked.insert(key);
+            }
+            Err(_) => eprintln!(
+                "Ignoring invalid key on line {} of {}",
+                i + 1,
+                path.display()
+            ),
+        }
+    }
+    Ok(revoked)
+}
+
+fn load_revoked(path: &Path) -> io::Result<HashSet<PublicKey>> {
+    Ok(load_key_list(path)?
+        .iter()
+        .map(|key| PublicKey::from(key.as_slice()))
+        .collect())
+}
+
+fn load_trusted_cas(path: &Path) -> io::Result<Vec<VerifyingKey>> {
+    let mut cas = Vec::new();
+    for key in load_key_list(path)? {
+        match <[u8; 32]>::try_from(key.as_slice())
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+        {
+            Some(ca) => cas.push(ca),
+            None => eprintln!(
+                "Ignoring invalid CA key {} in {}",
+                hex::encode(&key),
+                path.display()
+            ),
+        }
+    }
+    Ok(cas)
+}
+
+/// Adds a key to the revocation list, noting who it belonged to.
+pub fn add_revoked(base: impl AsRef<Path>, key: &[u8], comment: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(base.as_ref().join(REVOKED_FILE))?;
+    writeln!(file, "{} # {}", hex::encode(key), comment)
+}
+
+/// Stores a peer's public key as `allowed/<name>`. An existing file is only
+/// accepted if it already holds the same key, in either format.
+pub fn add_allowed(base: impl AsRef<Path>, name: &str, key: &[u8]) -> io::Result<()> {
+    if !valid_peer_name(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid peer name `{}`", name),
+        ));
+    }
+
+    let path = base.as_ref().join(ALLOWED_DIR).join(name);
+    match fs::read_to_string(&path).and_then(|text| Peer::parse(name, &text)) {
+        Ok(existing) if existing.public_key == key => Ok(()),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("A different key is already stored for `{}`", name),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::write(path, hex::encode(key)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Swaps the key stored in `allowed/<name>` for a new one, keeping the rest of the file.
+pub fn replace_allowed_key(base: impl AsRef<Path>, name: &str, key: &[u8]) -> io::Result<()> {
+    let path = base.as_ref().join(ALLOWED_DIR).join(name);
+    let text = fs::read_to_string(&path)?;
+
+    let text = if hex::decode(text.trim()).is_ok() {
+        hex::encode(key)
+    } else {
+        text.lines()
+            .map(|line| match line.split_once('=') {
+                Some((k, _)) if k.trim() == "public_key" => {
+                    format!("public_key = {}", hex::encode(key))
+                }
+                _ => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text + "\n")?;
+    fs::rename(tmp, path)
+}
+
+/// Peer names double as file names, so keep them boring.
+pub fn valid_peer_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
 fn read_hex(path: &Path) -> io::Result<Vec<u8>> {
@@ -106,10 +447,11 @@ fn read_hex(path: &Path) -> io::Result<Vec<u8>> {
     Ok(res)
 }
 
-fn generate_static_keypair() -> Keypair {
-    Builder::new(NOISE_PARAMS.parse().unwrap())
+fn generate_static_keypair() -> StaticKeypair {
+    Builder::new(DEFAULT_SUITE.params(Pattern::IK))
         .generate_keypair()
         .unwrap()
+        .into()
 }
 
 pub fn setup_keys_server(base: &Path) -> io::Result<()> {
@@ -118,11 +460,8 @@ pub fn setup_keys_server(base: &Path) -> io::Result<()> {
     // Create priv and public key files for the server.
     let kp = generate_static_keypair();
 
-    let pub_hex = hex::encode(&kp.public);
-    let priv_hex = hex::encode(&kp.private);
-
-    fs::write(base.join(PRIV_FILE), priv_hex)?;
-    fs::write(base.join(PUB_FILE), pub_hex)?;
+    write_private_key(&base.join(PRIV_FILE), &kp.private, None)?;
+    fs::write(base.join(PUB_FILE), hex::encode(&kp.public))?;
 
     Ok(())
 }
@@ -144,3 +483,50 @@ pub fn check_keys_setup(base: impl AsRef<Path>) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        check_keys_setup(dir.path()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotation_keeps_the_old_key_for_the_grace_period() {
+        let dir = base();
+        let base = dir.path();
+        let old = Auth::load(base).unwrap();
+
+        let new_public = rotate_static_key(base, Duration::from_secs(60)).unwrap();
+        let auth = Auth::load(base).unwrap();
+        assert_eq!(auth.keypair.public, new_public);
+        assert_ne!(auth.keypair.private, old.keypair.private);
+        let keys = auth.static_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].public, old.keypair.public);
+        assert_eq!(keys[1].private, old.keypair.private);
+
+        // Nothing staged is left behind, and the next rotation has to wait.
+        let names: Vec<_> = fs::read_dir(base)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(names.iter().all(|n| !n.ends_with(".new")), "{:?}", names);
+        assert!(rotate_static_key(base, Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn overlong_grace_periods_change_nothing() {
+        let dir = base();
+        let base = dir.path();
+        let old = Auth::load(base).unwrap();
+
+        assert!(rotate_static_key(base, Duration::from_secs(u64::MAX)).is_err());
+        let auth = Auth::load(base).unwrap();
+        assert_eq!(auth.keypair.public, old.keypair.public);
+        assert!(auth.previous.is_none());
+    }
+}
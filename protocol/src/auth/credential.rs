@@ -0,0 +1,243 @@
+/* A credential lets a client in without the server holding a copy of its key. An offline CA
+ * signs the client's static key together with a name, a validity period and attributes, and
+ * the client sends it after the timestamp in its first handshake message, where it is already
+ * encrypted. Servers only need the CA's public key, listed in `trusted_cas`.
+ *
+ * [ VERSION 1 ] [ CLIENT KEY 32 ] [ NOT BEFORE 8 ] [ NOT AFTER 8 ]
+ * [ NAME LEN 1 ] [ NAME ] [ ATTRIBUTES LEN 2 ] [ ATTRIBUTES ] [ SIGNATURE 64 ]
+ *
+ * Times are big endian Unix seconds. The attributes are peer file lines (see `peer`), e.g.
+ * `tags = contractor`. The signature is Ed25519 over `SIGNING_CONTEXT` followed by everything
+ * in front of it.
+ */
+
+use crate::auth::peer::Peer;
+use crate::auth::valid_peer_name;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const SIGNING_CONTEXT: &[u8] = b"blackwire credential";
+
+pub const CREDENTIAL_FILE: &str = "credential";
+
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub public_key: Vec<u8>,
+    pub name: String,
+    pub not_before: u64,
+    pub not_after: u64,
+    pub attributes: String,
+}
+
+impl Credential {
+    pub fn sign(&self, ca: &SigningKey) -> io::Result<Vec<u8>> {
+        if self.public_key.len() != KEY_LEN {
+            return Err(invalid("Client key must be 32 bytes"));
+        }
+        if !valid_peer_name(&self.name) || self.name.len() > u8::MAX as usize {
+            return Err(invalid("Invalid peer name"));
+        }
+        // Checked up front, so a CA can't sign something servers will refuse to parse.
+        self.to_peer()?;
+        let attributes_len =
+            u16::try_from(self.attributes.len()).map_err(|_| invalid("Attributes too long"))?;
+
+        let mut data = vec![VERSION];
+        data.extend_from_slice(&self.public_key);
+        data.extend_from_slice(&self.not_before.to_be_bytes());
+        data.extend_from_slice(&self.not_after.to_be_bytes());
+        data.push(self.name.len() as u8);
+        data.extend_from_slice(self.name.as_bytes());
+        data.extend_from_slice(&attributes_len.to_be_bytes());
+        data.extend_from_slice(self.attributes.as_bytes());
+
+        let signature = ca.sign(&signed_message(&data));
+        data.extend_from_slice(&signature.to_bytes());
+        Ok(data)
+    }
+
+    /// Parses a credential, accepting it only if one of `trusted` signed it.
+    pub fn verify(data: &[u8], trusted: &[VerifyingKey]) -> io::Result<Self> {
+        let body_len = data
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or_else(|| invalid("Credential too short"))?;
+        let (body, signature) = data.split_at(body_len);
+        let signature = Signature::from_slice(signature).map_err(|_| invalid("Bad signature"))?;
+
+        let message = signed_message(body);
+        if !trusted
+            .iter()
+            .any(|ca| ca.verify(&message, &signature).is_ok())
+        {
+            return Err(invalid("Credential isn't signed by a trusted CA"));
+        }
+
+        let mut reader = Reader(body);
+        if reader.take(1)? != [VERSION] {
+            return Err(invalid("Unknown credential version"));
+        }
+        let public_key = reader.take(KEY_LEN)?.to_vec();
+        let not_before = u64::from_be_bytes(reader.take(8)?.try_into().unwrap());
+        let not_after = u64::from_be_bytes(reader.take(8)?.try_into().unwrap());
+        let name_len = reader.take(1)?[0] as usize;
+        let name = String::from_utf8(reader.take(name_len)?.to_vec())
+            .map_err(|_| invalid("Invalid name"))?;
+        let attributes_len = u16::from_be_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+        let attributes = String::from_utf8(reader.take(attributes_len)?.to_vec())
+            .map_err(|_| invalid("Invalid attributes"))?;
+        if !reader.0.is_empty() {
+            return Err(invalid("Trailing data in credential"));
+        }
+
+        Ok(Self {
+            public_key,
+            name,
+            not_before,
+            not_after,
+            attributes,
+        })
+    }
+
+    /// The peer the credential describes. Its key and validity always come from the
+    /// credential itself, never from the attributes.
+    pub fn to_peer(&self) -> io::Result<Peer> {
+        let mut peer = Peer::parse(
+            &self.name,
+            &format!(
+                "{}\npublic_key = {}",
+                self.attributes,
+                hex::encode(&self.public_key)
+            ),
+        )?;
+        peer.not_before = Some(self.not_before);
+        peer.not_after = Some(self.not_after);
+        peer.psk = None;
+        Ok(peer)
+    }
+}
+
+fn signed_message(body: &[u8]) -> Vec<u8> {
+    [SIGNING_CONTEXT, body].concat()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("Credential too short"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the hex credential a client was issued, if it has one.
+pub fn load_credential(base: impl AsRef<Path>) -> io::Result<Option<Vec<u8>>> {
+    match fs::read_to_string(base.as_ref().join(CREDENTIAL_FILE)) {
+        Ok(text) => hex::decode(text.trim())
+            .map(Some)
+            .map_err(|_| invalid("Invalid hex in credential file")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ca(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn credential() -> Credential {
+        Credential {
+            public_key: vec![1; KEY_LEN],
+            name: "contractor".to_string(),
+            not_before: 100,
+            not_after: 200,
+            attributes: "tags = contractor\npsk = 3a9d".to_string(),
+        }
+    }
+
+    // Signs `body` as it stands, for credentials `sign` would never produce.
+    fn sign_raw(ca: &SigningKey, body: &[u8]) -> Vec<u8> {
+        [body, &ca.sign(&signed_message(body)).to_bytes()].concat()
+    }
+
+    #[test]
+    fn signed_credentials_verify() {
+        let data = credential().sign(&ca(1)).unwrap();
+        let trusted = [ca(2).verifying_key(), ca(1).verifying_key()];
+        let verified = Credential::verify(&data, &trusted).unwrap();
+        assert_eq!(verified.public_key, credential().public_key);
+        assert_eq!(verified.name, "contractor");
+        assert_eq!((verified.not_before, verified.not_after), (100, 200));
+
+        let peer = verified.to_peer().unwrap();
+        assert_eq!(peer.tags, ["contractor"]);
+        assert_eq!(peer.psk, None);
+        assert_eq!(peer.not_after, Some(200));
+    }
+
+    #[test]
+    fn other_cas_are_not_trusted() {
+        let data = credential().sign(&ca(1)).unwrap();
+        assert!(Credential::verify(&data, &[ca(2).verifying_key()]).is_err());
+        assert!(Credential::verify(&data, &[]).is_err());
+    }
+
+    #[test]
+    fn tampered_credentials_are_refused() {
+        let mut data = credential().sign(&ca(1)).unwrap();
+        data[1] ^= 1;
+        assert!(Credential::verify(&data, &[ca(1).verifying_key()]).is_err());
+    }
+
+    #[test]
+    fn trailing_data_is_refused() {
+        let data = credential().sign(&ca(1)).unwrap();
+        let body = [&data[..data.len() - SIGNATURE_LEN], &[0]].concat();
+        let e = Credential::verify(&sign_raw(&ca(1), &body), &[ca(1).verifying_key()]);
+        assert!(e.unwrap_err().to_string().contains("Trailing data"));
+    }
+
+    #[test]
+    fn truncated_credentials_are_refused() {
+        let trusted = [ca(1).verifying_key()];
+        let data = credential().sign(&ca(1)).unwrap();
+        let body = &data[..data.len() - SIGNATURE_LEN];
+
+        for len in [0, 1, SIGNATURE_LEN - 1, data.len() - 1] {
+            assert!(Credential::verify(&data[..len], &trusted).is_err());
+        }
+        // Even when the CA signed the short version.
+        for len in [0, 1, KEY_LEN, body.len() - 1] {
+            let e = Credential::verify(&sign_raw(&ca(1), &body[..len]), &trusted);
+            assert!(e.unwrap_err().to_string().contains("too short"));
+        }
+    }
+
+    #[test]
+    fn unparsable_credentials_are_not_signed() {
+        let mut bad = credential();
+        bad.name = "no spaces".to_string();
+        assert!(bad.sign(&ca(1)).is_err());
+
+        let mut bad = credential();
+        bad.attributes = "vlan = 0".to_string();
+        assert!(bad.sign(&ca(1)).is_err());
+    }
+}
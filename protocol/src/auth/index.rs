@@ -0,0 +1,138 @@
+/* Peers are looked up by public key on every handshake, so they are indexed by it as well as by
+ * name. Keys compare in constant time, and `HashMap`'s hasher is keyed randomly per process, so
+ * how long a lookup takes says nothing about which keys are allowed.
+ */
+
+use crate::auth::peer::Peer;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use subtle::ConstantTimeEq;
+
+/// A public key that compares in constant time.
+#[derive(Debug, Clone, Eq)]
+pub struct PublicKey(Vec<u8>);
+
+impl From<&[u8]> for PublicKey {
+    fn from(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+/// Everyone in `allowed/`, by name and by key.
+#[derive(Debug, Default)]
+pub struct PeerIndex {
+    by_name: HashMap<String, Peer>,
+    names: HashMap<PublicKey, String>,
+}
+
+impl PeerIndex {
+    /// Indexes `peers`. Of several peers with the same key, only the first by name is kept.
+    pub fn new(peers: HashMap<String, Peer>) -> Self {
+        let mut peers: Vec<Peer> = peers.into_values().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut index = Self::default();
+        for peer in peers {
+            let key = PublicKey::from(peer.public_key.as_slice());
+            if let Some(name) = index.names.get(&key) {
+                eprintln!("Ignoring peer {}, it has the same key as `{}`", peer, name);
+                continue;
+            }
+            index.names.insert(key, peer.name.clone());
+            index.by_name.insert(peer.name.clone(), peer);
+        }
+        index
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Peer> {
+        self.by_name.get(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+
+    /// The peer a public key belongs to.
+    pub fn by_key(&self, key: &[u8]) -> Option<&Peer> {
+        self.name_for(key).and_then(|name| self.by_name.get(name))
+    }
+
+    /// The name of the peer a public key belongs to.
+    pub fn name_for(&self, key: &[u8]) -> Option<&str> {
+        self.names.get(&PublicKey::from(key)).map(String::as_str)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Peer> {
+        self.by_name.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(peers: &[(&str, u8)]) -> PeerIndex {
+        PeerIndex::new(
+            peers
+                .iter()
+                .map(|&(name, key)| (name.to_string(), Peer::new(name, vec![key; 32])))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn peers_are_found_by_name_and_key() {
+        let index = index(&[("laptop", 1), ("phone", 2)]);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("laptop").unwrap().public_key, [1; 32]);
+        assert_eq!(index.name_for(&[2; 32]), Some("phone"));
+        assert_eq!(index.by_key(&[1; 32]).unwrap().name, "laptop");
+        assert!(index.by_key(&[3; 32]).is_none());
+        assert!(index.by_key(&[1; 31]).is_none());
+        assert!(!index.contains_key("tablet"));
+    }
+
+    #[test]
+    fn shared_keys_go_to_the_first_name() {
+        let index = index(&[("zebra", 1), ("alpha", 1), ("mid", 2)]);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.name_for(&[1; 32]), Some("alpha"));
+        assert!(!index.contains_key("zebra"));
+    }
+
+    #[test]
+    fn keys_compare_by_value() {
+        assert_eq!(
+            PublicKey::from(&[1u8; 32][..]),
+            PublicKey::from(&[1u8; 32][..])
+        );
+        assert_ne!(
+            PublicKey::from(&[1u8; 32][..]),
+            PublicKey::from(&[2u8; 32][..])
+        );
+        assert_ne!(
+            PublicKey::from(&[1u8; 32][..]),
+            PublicKey::from(&[1u8; 31][..])
+        );
+    }
+}
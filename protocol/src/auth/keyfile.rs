@@ -0,0 +1,296 @@
+/* Private keys are kept hex encoded in files only their owner can read, and refused if anyone
+ * else can. On laptops a key can also be encrypted with a passphrase, in which case the file
+ * holds `key = value` lines (see `conf`) instead:
+ *
+ *   kdf = argon2id
+ *   salt = <hex>
+ *   nonce = <hex>
+ *   key = <hex ChaCha20-Poly1305 ciphertext of the key>
+ *
+ * The passphrase comes from `BLACKWIRE_PASSPHRASE`, or is asked for on the terminal once per run.
+ */
+
+use crate::conf::Conf;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use snow::Keypair;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::ops::Deref;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::OnceLock;
+use zeroize::{Zeroize, Zeroizing};
+
+pub const PASSPHRASE_ENV: &str = "BLACKWIRE_PASSPHRASE";
+
+const KDF: &str = "argon2id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+// Pinned rather than taken from the crate, so files stay readable if its defaults change.
+const KDF_MEMORY_KIB: u32 = 19 * 1024;
+const KDF_ITERATIONS: u32 = 2;
+const AAD: &[u8] = b"blackwire private key";
+
+static PASSPHRASE: OnceLock<Zeroizing<String>> = OnceLock::new();
+
+/// A static keypair whose private half is wiped when the last reference goes.
+pub struct StaticKeypair(Keypair);
+
+impl StaticKeypair {
+    pub fn new(private: &[u8], public: Vec<u8>) -> Self {
+        Self(Keypair {
+            private: private.to_vec(),
+            public,
+        })
+    }
+}
+
+impl From<Keypair> for StaticKeypair {
+    fn from(keypair: Keypair) -> Self {
+        Self(keypair)
+    }
+}
+
+impl Deref for StaticKeypair {
+    type Target = Keypair;
+
+    fn deref(&self) -> &Keypair {
+        &self.0
+    }
+}
+
+impl Drop for StaticKeypair {
+    fn drop(&mut self) {
+        self.0.private.zeroize();
+    }
+}
+
+/// Reads a private key, decrypting it if it was stored with a passphrase.
+pub fn read_private_key(path: &Path) -> io::Result<Zeroizing<Vec<u8>>> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is readable by everyone, run `chmod 600` on it",
+                path.display()
+            ),
+        ));
+    }
+
+    let text = Zeroizing::new(fs::read_to_string(path)?);
+    if let Ok(key) = hex::decode(text.trim()) {
+        return Ok(Zeroizing::new(key));
+    }
+
+    decrypt_key(&Conf::parse(&text)?, passphrase()?)
+}
+
+fn decrypt_key(conf: &Conf, passphrase: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    if conf.get("kdf") != Some(KDF) {
+        return Err(invalid("Unknown key derivation in private key file"));
+    }
+    let field = |key| {
+        conf.get(key)
+            .and_then(|v| hex::decode(v).ok())
+            .ok_or_else(|| invalid(&format!("Missing or invalid `{}` in private key file", key)))
+    };
+    let salt = field("salt")?;
+    let nonce = field("nonce")?;
+    let ciphertext = field("key")?;
+    if nonce.len() != NONCE_LEN {
+        return Err(invalid("Invalid nonce in private key file"));
+    }
+
+    let cipher = cipher(passphrase, &salt)?;
+    cipher
+        .decrypt(
+            nonce.as_slice().into(),
+            Payload {
+                msg: &ciphertext,
+                aad: AAD,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Wrong passphrase for private key",
+            )
+        })
+}
+
+/// Writes a private key readable only by us, encrypted if a passphrase is given. The old file,
+/// if any, is only replaced once the new one is complete.
+pub fn write_private_key(path: &Path, key: &[u8], passphrase: Option<&str>) -> io::Result<()> {
+    let text = Zeroizing::new(match passphrase {
+        None => hex::encode(key),
+        Some(passphrase) => {
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            rand::thread_rng().fill_bytes(&mut nonce);
+
+            let ciphertext = cipher(passphrase, &salt)?
+                .encrypt(&nonce.into(), Payload { msg: key, aad: AAD })
+                .map_err(|_| io::Error::other("Failed to encrypt private key"))?;
+            format!(
+                "kdf = {}\nsalt = {}\nnonce = {}\nkey = {}",
+                KDF,
+                hex::encode(salt),
+                hex::encode(nonce),
+                hex::encode(ciphertext)
+            )
+        }
+    });
+
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    // `mode` only applies to new files.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    writeln!(file, "{}", text.as_str())?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> io::Result<ChaCha20Poly1305> {
+    let params = Params::new(KDF_MEMORY_KIB, KDF_ITERATIONS, 1, Some(KEY_LEN))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(key.as_ref().into()))
+}
+
+/// Whether the key in `path` is stored with a passphrase.
+pub fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let text = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(hex::decode(text.trim()).is_err())
+}
+
+/// The passphrase for encrypted keys, asked for at most once.
+pub fn passphrase() -> io::Result<&'static str> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Zeroizing::new(passphrase),
+        Err(_) => read_passphrase("Passphrase for private key: ").map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Private key is encrypted, set {}: {}", PASSPHRASE_ENV, e),
+            )
+        })?,
+    };
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
+}
+
+/// Asks for a passphrase on the terminal without echoing it.
+pub fn read_passphrase(prompt: &str) -> io::Result<Zeroizing<String>> {
+    let fd = libc::STDIN_FILENO;
+    if unsafe { libc::isatty(fd) } != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "No terminal to ask for a passphrase on",
+        ));
+    }
+
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let old = term;
+    term.c_lflag &= !libc::ECHO;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    eprint!("{}", prompt);
+    let mut line = Zeroizing::new(String::new());
+    let read = io::stdin().lock().read_line(&mut line);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &old) };
+    eprintln!();
+    read?;
+
+    Ok(Zeroizing::new(
+        line.trim_end_matches(['\r', '\n']).to_string(),
+    ))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    /// A key file path in a directory that is removed when the test is done.
+    fn key_file() -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("private.key");
+        (dir, path)
+    }
+
+    fn stored(path: &Path) -> Conf {
+        Conf::parse(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn plain_keys_round_trip_privately() {
+        let (_dir, path) = key_file();
+        write_private_key(&path, &KEY, None).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!is_encrypted(&path).unwrap());
+        assert_eq!(*read_private_key(&path).unwrap(), KEY);
+    }
+
+    #[test]
+    fn world_readable_keys_are_refused() {
+        let (_dir, path) = key_file();
+        write_private_key(&path, &KEY, None).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let e = read_private_key(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn encrypted_keys_round_trip() {
+        let (_dir, path) = key_file();
+        write_private_key(&path, &KEY, Some("correct horse")).unwrap();
+        assert!(is_encrypted(&path).unwrap());
+        assert!(
+            !fs::read_to_string(&path)
+                .unwrap()
+                .contains(&hex::encode(KEY))
+        );
+        assert_eq!(*decrypt_key(&stored(&path), "correct horse").unwrap(), KEY);
+    }
+
+    #[test]
+    fn wrong_passphrases_are_refused() {
+        let (_dir, path) = key_file();
+        write_private_key(&path, &KEY, Some("correct horse")).unwrap();
+        let e = decrypt_key(&stored(&path), "battery staple").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
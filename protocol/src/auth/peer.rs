@@ -0,0 +1,315 @@
+/* Each file in `allowed/` describes one peer and is named after it. The file holds either just the
+ * peer's hex public key, or `key = value` lines (see `conf`):
+ *
+ *   public_key = 8f2c...
+ *   display_name = Alice's laptop
+ *   enabled = true
+ *   not_before = 1735689600
+ *   not_after = 1767225600
+ *   mac = 02:00:00:00:00:01
+ *   vlan = 20
+ *   rate_limit = 10000
+ *   psk = 3a9d...
+ *   tags = staff, laptop
+ *   idle_timeout = 600
+ *   max_session_lifetime = 86400
+ *   compression = lz4
+ *
+ * Only `public_key` is required. `expires` is still read as an older name for `not_after`. A `psk`
+ * here takes precedence over an `allowed/<name>.psk` file.
+ */
+
+use crate::compress::Compression;
+use crate::conf::Conf;
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Peers this close to expiring are warned about in logs and `server status`.
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// The file name in `allowed/`, which is what the peer is known as.
+    pub name: String,
+    pub public_key: Vec<u8>,
+    pub display_name: Option<String>,
+    /// Disabled peers keep their file but can't connect.
+    pub enabled: bool,
+    /// Unix time before which the peer can't connect yet.
+    pub not_before: Option<u64>,
+    /// Unix time from which the peer can no longer connect.
+    pub not_after: Option<u64>,
+    /// MAC the peer's end of the tunnel is given instead of a random one.
+    pub mac: Option<[u8; 6]>,
+    pub vlan: Option<u16>,
+    /// In kbit/s.
+    pub rate_limit: Option<u32>,
+    pub psk: Option<Vec<u8>>,
+    pub tags: Vec<String>,
+    /// Overrides the server's `idle_timeout`, in seconds. 0 means none.
+    pub idle_timeout: Option<u64>,
+    /// Overrides the server's `max_session_lifetime`, in seconds. 0 means none.
+    pub max_session_lifetime: Option<u64>,
+    /// Compression the peer may negotiate.
+    pub compression: Compression,
+    /// The signed credential the peer was let in with, if it has no peer file.
+    pub credential: Option<Vec<u8>>,
+}
+
+impl Peer {
+    /// A peer known only by its key, as stored in the original bare hex files.
+    pub fn new(name: &str, public_key: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            public_key,
+            display_name: None,
+            enabled: true,
+            not_before: None,
+            not_after: None,
+            mac: None,
+            vlan: None,
+            rate_limit: None,
+            psk: None,
+            tags: Vec::new(),
+            idle_timeout: None,
+            max_session_lifetime: None,
+            compression: Compression::None,
+            credential: None,
+        }
+    }
+
+    /// Parses a peer file in either format.
+    pub fn parse(name: &str, text: &str) -> io::Result<Self> {
+        if let Ok(key) = hex::decode(text.trim()) {
+            return Ok(Self::new(name, key));
+        }
+
+        let conf = Conf::parse(text)?;
+        let public_key = conf
+            .get("public_key")
+            .ok_or_else(|| invalid("Missing `public_key`".to_string()))
+            .and_then(|v| decode_hex("public_key", v))?;
+
+        let vlan = conf.get_parsed("vlan")?;
+        if vlan.is_some_and(|vlan: u16| !(1..=4094).contains(&vlan)) {
+            return Err(invalid("`vlan` must be between 1 and 4094".to_string()));
+        }
+
+        Ok(Self {
+            display_name: conf.get_string("display_name"),
+            enabled: conf.get_or("enabled", true)?,
+            not_before: conf.get_parsed("not_before")?,
+            not_after: match conf.get_parsed("not_after")? {
+                Some(not_after) => Some(not_after),
+                None => conf.get_parsed("expires")?,
+            },
+            mac: conf.get("mac").map(parse_mac).transpose()?,
+            vlan,
+            rate_limit: conf.get_parsed("rate_limit")?,
+            psk: conf.get("psk").map(|v| decode_hex("psk", v)).transpose()?,
+            tags: conf
+                .get("tags")
+                .map(|tags| {
+                    tags.split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            idle_timeout: conf.get_parsed("idle_timeout")?,
+            max_session_lifetime: conf.get_parsed("max_session_lifetime")?,
+            compression: conf.get_or("compression", Compression::None)?,
+            ..Self::new(name, public_key)
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.not_after.is_some_and(|not_after| now() >= not_after)
+    }
+
+    pub fn is_not_yet_valid(&self) -> bool {
+        self.not_before.is_some_and(|not_before| now() < not_before)
+    }
+
+    /// Whether the peer may connect at all.
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.is_expired() && !self.is_not_yet_valid()
+    }
+
+    /// How long until the peer expires, if it will.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.not_after
+            .map(|not_after| Duration::from_secs(not_after.saturating_sub(now())))
+    }
+
+    /// A warning for peers that expire within `EXPIRY_WARNING`.
+    pub fn expiry_warning(&self) -> Option<String> {
+        self.expires_in()
+            .filter(|left| !left.is_zero() && *left < EXPIRY_WARNING)
+            .map(|left| format!("Peer {} expires in {}", self, format_duration(left)))
+    }
+
+    /// The idle timeout for this peer, given the server wide one.
+    pub fn idle_timeout_or(&self, default: Option<Duration>) -> Option<Duration> {
+        override_secs(self.idle_timeout, default)
+    }
+
+    /// The session lifetime for this peer, given the server wide one.
+    pub fn max_session_lifetime_or(&self, default: Option<Duration>) -> Option<Duration> {
+        override_secs(self.max_session_lifetime, default)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.display_name {
+            Some(display_name) => write!(f, "`{}` ({})", self.name, display_name),
+            None => write!(f, "`{}`", self.name),
+        }
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Rounds down to the largest unit, e.g. `3d 4h` or `25m`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        86400.. => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+        3600.. => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        60.. => format!("{}m", secs / 60),
+        _ => format!("{}s", secs),
+    }
+}
+
+fn override_secs(secs: Option<u64>, default: Option<Duration>) -> Option<Duration> {
+    match secs {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => default,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn decode_hex(key: &str, value: &str) -> io::Result<Vec<u8>> {
+    hex::decode(value).map_err(|_| invalid(format!("Invalid hex for `{}`", key)))
+}
+
+/// Parses `02:00:00:00:00:01`. Only unicast addresses can be given to a peer, so broadcast,
+/// multicast and all-zero ones are refused.
+fn parse_mac(s: &str) -> io::Result<[u8; 6]> {
+    let mac: [u8; 6] = s
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| invalid(format!("Invalid MAC address `{}`", s)))?;
+
+    if mac[0] & 1 != 0 || mac == [0; 6] {
+        return Err(invalid(format!("`{}` is not a unicast MAC address", s)));
+    }
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "8f2c000000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn bare_keys_are_peers_with_defaults() {
+        let peer = Peer::parse("laptop", &format!("{}\n", KEY)).unwrap();
+        assert_eq!(peer.name, "laptop");
+        assert_eq!(hex::encode(&peer.public_key), KEY);
+        assert!(peer.enabled);
+        assert!(peer.is_active());
+        assert_eq!(peer.mac, None);
+    }
+
+    #[test]
+    fn peer_files_are_parsed() {
+        let text = format!(
+            "public_key = {}\n\
+             display_name = Alice's laptop\n\
+             enabled = false\n\
+             not_before = 100\n\
+             expires = 200\n\
+             mac = 02:00:00:00:00:01\n\
+             vlan = 20\n\
+             rate_limit = 10000\n\
+             psk = 3a9d\n\
+             tags = staff, , laptop\n\
+             idle_timeout = 0\n\
+             max_session_lifetime = 86400\n",
+            KEY
+        );
+        let peer = Peer::parse("laptop", &text).unwrap();
+        assert_eq!(peer.display_name.as_deref(), Some("Alice's laptop"));
+        assert!(!peer.enabled);
+        assert_eq!(peer.not_before, Some(100));
+        assert_eq!(peer.not_after, Some(200));
+        assert_eq!(peer.mac, Some([2, 0, 0, 0, 0, 1]));
+        assert_eq!(peer.vlan, Some(20));
+        assert_eq!(peer.rate_limit, Some(10000));
+        assert_eq!(peer.psk, Some(vec![0x3a, 0x9d]));
+        assert_eq!(peer.tags, ["staff", "laptop"]);
+        assert_eq!(peer.idle_timeout_or(Some(Duration::from_secs(60))), None);
+        assert_eq!(
+            peer.max_session_lifetime_or(None),
+            Some(Duration::from_secs(86400))
+        );
+        assert!(peer.is_expired());
+        assert!(!peer.is_active());
+    }
+
+    #[test]
+    fn not_after_wins_over_expires() {
+        let text = format!("public_key = {}\nnot_after = 300\nexpires = 200\n", KEY);
+        assert_eq!(Peer::parse("p", &text).unwrap().not_after, Some(300));
+    }
+
+    #[test]
+    fn invalid_peer_files_are_refused() {
+        for text in [
+            "display_name = no key".to_string(),
+            "public_key = xyz".to_string(),
+            format!("public_key = {}\nvlan = 0", KEY),
+            format!("public_key = {}\nvlan = 4095", KEY),
+            format!("public_key = {}\nenabled = maybe", KEY),
+            format!("public_key = {}\nmac = 02:00:00:00:01", KEY),
+            format!("public_key = {}\npsk = zz", KEY),
+        ] {
+            assert!(Peer::parse("p", &text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn only_unicast_macs_are_accepted() {
+        assert_eq!(
+            parse_mac("0a:1b:2c:3d:4e:5f").unwrap(),
+            [10, 27, 44, 61, 78, 95]
+        );
+        for mac in [
+            "ff:ff:ff:ff:ff:ff",
+            "01:00:5e:00:00:01",
+            "33:33:00:00:00:01",
+            "00:00:00:00:00:00",
+            "02:00:00:00:00",
+            "02:00:00:00:00:01:02",
+            "02:00:00:00:00:zz",
+        ] {
+            assert!(parse_mac(mac).is_err(), "{}", mac);
+        }
+    }
+}
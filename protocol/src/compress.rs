@@ -0,0 +1,145 @@
+use crate::framing::{FEATURE_LZ4, OpCode};
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    /// The feature bit advertised in the Handshake control frame.
+    pub fn feature(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => FEATURE_LZ4,
+        }
+    }
+
+    /// Picks the algorithm to use from the negotiated features.
+    pub fn from_features(features: u32) -> Self {
+        if features & FEATURE_LZ4 != 0 {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        match s {
+            "none" | "off" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(io::Error::other(format!("Unknown compression `{}`", s))),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Lz4 = 0,
+}
+
+/// Compresses a whole message, keeping the original if it doesn't shrink.
+pub fn compress_message(msg: Vec<u8>, compression: Compression) -> Vec<u8> {
+    let body = match compression {
+        Compression::None => return msg,
+        Compression::Lz4 => lz4_flex::block::compress(&msg),
+    };
+
+    // [ OP=6 ] [ ALG ] [ LEN ] [ DATA ]
+    if 4 + body.len() >= msg.len() || msg.len() > u16::MAX as usize {
+        return msg;
+    }
+
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.push(OpCode::Compressed as u8);
+    out.push(Algorithm::Lz4 as u8);
+    out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Restores the original message if `data` is compressed, otherwise returns it unchanged.
+/// Compressed messages are refused unless `compression` was negotiated.
+pub fn decompress_message(data: Vec<u8>, compression: Compression) -> io::Result<Vec<u8>> {
+    if data.first() != Some(&(OpCode::Compressed as u8)) {
+        return Ok(data);
+    }
+    if compression == Compression::None {
+        return Err(io::Error::other(
+            "Compressed frame without negotiated compression",
+        ));
+    }
+
+    if data.len() < 4 {
+        return Err(io::Error::other("Compressed frame too short"));
+    }
+    if data[1] != Algorithm::Lz4 as u8 {
+        return Err(io::Error::other("Unknown compression algorithm"));
+    }
+
+    // The length is capped at 16 bits, so a hostile peer can't make us allocate much.
+    let len = BigEndian::read_u16(&data[2..4]) as usize;
+    let mut out = vec![0u8; len];
+    let n = lz4_flex::block::decompress_into(&data[4..], &mut out).map_err(io::Error::other)?;
+    if n != len {
+        return Err(io::Error::other("Compressed frame length mismatch"));
+    }
+
+    if out.first() == Some(&(OpCode::Compressed as u8)) {
+        return Err(io::Error::other("Nested compressed frame"));
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_messages_need_negotiated_compression() {
+        let msg = [&[OpCode::Ethernet as u8][..], &[0u8; 200]].concat();
+        let compressed = compress_message(msg.clone(), Compression::Lz4);
+        assert_eq!(compressed[0], OpCode::Compressed as u8);
+
+        assert!(decompress_message(compressed.clone(), Compression::None).is_err());
+        assert_eq!(
+            decompress_message(compressed, Compression::Lz4).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn incompressible_messages_are_sent_as_they_are() {
+        let msg: Vec<u8> = (0..200u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert_eq!(compress_message(msg.clone(), Compression::Lz4), msg);
+
+        // Too small to win anything either.
+        let tiny = vec![OpCode::Ethernet as u8, 0, 0];
+        assert_eq!(compress_message(tiny.clone(), Compression::Lz4), tiny);
+        assert_eq!(compress_message(tiny.clone(), Compression::None), tiny);
+    }
+
+    #[test]
+    fn wrong_lengths_are_refused() {
+        let msg = [&[OpCode::Ethernet as u8][..], &[0u8; 200]].concat();
+        let mut compressed = compress_message(msg, Compression::Lz4);
+        compressed[2..4].copy_from_slice(&150u16.to_be_bytes());
+        assert!(decompress_message(compressed.clone(), Compression::Lz4).is_err());
+        compressed[2..4].copy_from_slice(&300u16.to_be_bytes());
+        assert!(decompress_message(compressed, Compression::Lz4).is_err());
+
+        let short = vec![OpCode::Compressed as u8, Algorithm::Lz4 as u8, 0];
+        assert!(decompress_message(short, Compression::Lz4).is_err());
+    }
+}
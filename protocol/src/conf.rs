@@ -0,0 +1,119 @@
+/* Config files are plain `key = value` lines:
+ *
+ *   # Comment
+ *   port = 52123
+ *   netns = lan
+ *
+ * Blank lines and lines starting with '#' are ignored. Later keys override earlier ones.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Default, Clone)]
+pub struct Conf {
+    entries: HashMap<String, String>,
+}
+
+impl Conf {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {}: expected `key = value`", i + 1),
+                )
+            })?;
+
+            entries.insert(key.trim().to_string(), value.trim().to_string());
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Loads a config file, treating a missing file as empty.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .get(key)
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        self.get(key).map(|v| v.to_string())
+    }
+
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> io::Result<T> {
+        Ok(self.get_parsed(key)?.unwrap_or(default))
+    }
+
+    /// Like `get_or`, for keys that have no default.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> io::Result<Option<T>> {
+        match self.get(key) {
+            Some(v) => v.parse().map(Some).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid value for `{}`: {}", key, v),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_comments_and_overrides() {
+        let conf = Conf::parse(
+            "# Comment\n\n  port = 52123  \nnetns=lan\nname = a = b\nport = 1\nempty =\n",
+        )
+        .unwrap();
+        assert_eq!(conf.get("port"), Some("1"));
+        assert_eq!(conf.get("netns"), Some("lan"));
+        assert_eq!(conf.get("name"), Some("a = b"));
+        assert_eq!(conf.get("empty"), None);
+        assert_eq!(conf.get("missing"), None);
+    }
+
+    #[test]
+    fn lines_without_equals_are_refused() {
+        let err = Conf::parse("port = 1\nnonsense\n").unwrap_err();
+        assert!(err.to_string().contains("Line 2"));
+    }
+
+    #[test]
+    fn values_are_parsed_or_defaulted() {
+        let conf = Conf::parse("port = 52123\nmtu = big\n").unwrap();
+        assert_eq!(conf.get_or("port", 1u16).unwrap(), 52123);
+        assert_eq!(conf.get_or("missing", 7u16).unwrap(), 7);
+        assert!(conf.get_or("mtu", 1400i32).is_err());
+        assert_eq!(conf.get_parsed::<u32>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let conf = Conf::load("/nonexistent/blackwire.conf").unwrap();
+        assert_eq!(conf.get("port"), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::framing::OpCode;
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+
+// [ OP=7 ] [ ID u32 ] [ INDEX u16 ] [ COUNT u16 ]
+const HEADER_LEN: usize = 9;
+
+/// Upper bound on a reassembled message, so a peer can't make us buffer forever.
+pub const MAX_REASSEMBLED: usize = 1 << 20;
+
+/// Splits `msg` into fragments of at most `max_len` bytes each. An empty message is a single
+/// empty fragment, so it still arrives as a message.
+pub fn fragment(msg: &[u8], id: u32, max_len: usize) -> io::Result<Vec<Vec<u8>>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    if msg.len() > MAX_REASSEMBLED {
+        return Err(invalid("Message too large to fragment"));
+    }
+    if max_len <= HEADER_LEN {
+        return Err(invalid("Fragments too small to carry anything"));
+    }
+
+    let mut chunks: Vec<&[u8]> = msg.chunks(max_len - HEADER_LEN).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let count = u16::try_from(chunks.len()).map_err(|_| invalid("Too many fragments"))?;
+
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut out = Vec::with_capacity(HEADER_LEN + chunk.len());
+            out.push(OpCode::Fragment as u8);
+            out.extend_from_slice(&id.to_be_bytes());
+            out.extend_from_slice(&(index as u16).to_be_bytes());
+            out.extend_from_slice(&count.to_be_bytes());
+            out.extend_from_slice(chunk);
+            out
+        })
+        .collect())
+}
+
+/// Rebuilds fragmented messages. The stream is ordered, so only one message
+/// can be in flight per direction; a new ID discards whatever was pending.
+#[derive(Default)]
+pub struct Reassembler {
+    id: u32,
+    next: u16,
+    count: u16,
+    buf: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a decrypted message. Unfragmented messages pass straight through,
+    /// fragments are held until the last one arrives.
+    pub fn push(&mut self, data: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if data.first() != Some(&(OpCode::Fragment as u8)) {
+            return Ok(Some(data));
+        }
+
+        if data.len() < HEADER_LEN {
+            return Err(io::Error::other("Fragment too short"));
+        }
+
+        let id = BigEndian::read_u32(&data[1..5]);
+        let index = BigEndian::read_u16(&data[5..7]);
+        let count = BigEndian::read_u16(&data[7..9]);
+
+        if index == 0 {
+            self.id = id;
+            self.next = 0;
+            self.count = count;
+            self.buf.clear();
+        }
+
+        if count == 0 || id != self.id || index != self.next || count != self.count {
+            self.reset();
+            return Err(io::Error::other("Out of order fragment"));
+        }
+
+        if self.buf.len() + data.len() - HEADER_LEN > MAX_REASSEMBLED {
+            self.reset();
+            return Err(io::Error::other("Reassembled message too large"));
+        }
+
+        self.buf.extend_from_slice(&data[HEADER_LEN..]);
+        self.next += 1;
+
+        if self.next < self.count {
+            return Ok(None);
+        }
+
+        let msg = std::mem::take(&mut self.buf);
+        self.reset();
+        Ok(Some(msg))
+    }
+
+    fn reset(&mut self) {
+        self.next = 0;
+        self.count = 0;
+        self.buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn raw_fragment(id: u32, index: u16, count: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![OpCode::Fragment as u8];
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&index.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn fragments_reassemble_in_order() {
+        let msg = message(1000);
+        let fragments = fragment(&msg, 1, 100).unwrap();
+        assert_eq!(fragments.len(), 11);
+
+        let mut r = Reassembler::new();
+        let (last, rest) = fragments.split_last().unwrap();
+        for f in rest {
+            assert_eq!(r.push(f.clone()).unwrap(), None);
+        }
+        assert_eq!(r.push(last.clone()).unwrap(), Some(msg));
+    }
+
+    #[test]
+    fn out_of_order_fragments_are_refused() {
+        let fragments = fragment(&message(300), 1, 100).unwrap();
+        let mut r = Reassembler::new();
+        r.push(fragments[0].clone()).unwrap();
+        assert!(r.push(fragments[2].clone()).is_err());
+        // The message is dropped, so the rest of it doesn't complete anything.
+        assert!(r.push(fragments[1].clone()).is_err());
+    }
+
+    #[test]
+    fn duplicate_fragments_are_refused() {
+        let msg = message(300);
+        let fragments = fragment(&msg, 1, 100).unwrap();
+        let mut r = Reassembler::new();
+        r.push(fragments[0].clone()).unwrap();
+        r.push(fragments[1].clone()).unwrap();
+        assert!(r.push(fragments[1].clone()).is_err());
+
+        // The next message starts cleanly.
+        let fragments = fragment(&msg, 2, 100).unwrap();
+        let results: Vec<_> = fragments.into_iter().map(|f| r.push(f).unwrap()).collect();
+        assert_eq!(results.last().unwrap().as_ref(), Some(&msg));
+    }
+
+    #[test]
+    fn overlapping_messages_are_refused() {
+        let a = fragment(&message(300), 1, 100).unwrap();
+        let b = fragment(&message(300), 2, 100).unwrap();
+        let mut r = Reassembler::new();
+        r.push(a[0].clone()).unwrap();
+        // A new message discards the pending one...
+        r.push(b[0].clone()).unwrap();
+        // ...so the rest of the old one no longer fits.
+        assert!(r.push(a[1].clone()).is_err());
+        // Fragments claiming a different count for the same message are refused too.
+        r.push(b[0].clone()).unwrap();
+        assert!(r.push(raw_fragment(2, 1, 9, &[0u8; 10])).is_err());
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        assert!(fragment(&message(MAX_REASSEMBLED + 1), 1, 1400).is_err());
+
+        let half = vec![0u8; MAX_REASSEMBLED / 2 + 1];
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(raw_fragment(1, 0, 2, &half)).unwrap(), None);
+        assert!(r.push(raw_fragment(1, 1, 2, &half)).is_err());
+    }
+
+    #[test]
+    fn short_fragments_are_refused() {
+        let mut r = Reassembler::new();
+        assert!(r.push(vec![OpCode::Fragment as u8, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn fragment_sizes_must_leave_room_for_data() {
+        assert!(fragment(b"data", 1, HEADER_LEN).is_err());
+        assert!(fragment(b"data", 1, 0).is_err());
+        assert_eq!(fragment(b"data", 1, HEADER_LEN + 1).unwrap().len(), 4);
+        // One byte per fragment, so one byte too many for a u16 to count.
+        assert!(fragment(&message(u16::MAX as usize + 1), 1, HEADER_LEN + 1).is_err());
+        assert!(fragment(&message(u16::MAX as usize), 1, HEADER_LEN + 1).is_ok());
+    }
+
+    #[test]
+    fn empty_messages_are_one_empty_fragment() {
+        let fragments = fragment(&[], 1, 100).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(
+            Reassembler::new().push(fragments[0].clone()).unwrap(),
+            Some(vec![])
+        );
+        assert!(Reassembler::new().push(raw_fragment(1, 0, 0, &[])).is_err());
+    }
+}
@@ -7,20 +7,78 @@
  * 2: IPv4
  * 3: Error
  * 4: Disconnect
+ * 5: Ethernet batch
+ * 6: Compressed
+ * 7: Fragment
  *
  * Control packets have a further ControlType byte.
  * [ OP=0 ] [ TYPE ] [ DATA ]
+ *
+ * Handshake control packets advertise optional features as a bitmask. The client sends its
+ * features once it has a MAC, the server answers with the ones both sides support. Peers that
+ * never answer (older versions) simply never get any optional features.
+ * [ OP=0 ] [ TYPE=0 ] [ FEATURES u32 ]
+ *
+ * Mtu control packets tell the peer the largest inner MTU our end of the path can carry.
+ * [ OP=0 ] [ TYPE=3 ] [ MTU u16 ]
+ *
+ * Rekey control packets are the last message sent under the old sending key. Everything after
+ * it uses the next key, so the receiver switches its receiving key as soon as it decrypts one.
+ * [ OP=0 ] [ TYPE=4 ]
+ *
+ * ServerKey control packets tell a client that connected with the server's previous static key
+ * which key replaced it. The session itself is authenticated with the old key, so the client can
+ * pin the new one without asking anyone.
+ * [ OP=0 ] [ TYPE=5 ] [ KEY 32 ]
+ *
+ * Disconnect messages are the last thing sent before hanging up, saying why.
+ * [ OP=4 ] [ REASON ] [ MESSAGE ]
+ *
+ * Ethernet batches carry several frames in one Noise message, each with a 2 byte length.
+ * [ OP=5 ] [ LEN ] [ FRAME ] [ LEN ] [ FRAME ] ...
+ *
+ * Compressed messages wrap another whole message (Ethernet or Ethernet batch). LEN is the size
+ * of the original message. Senders only compress when it actually saves space.
+ * [ OP=6 ] [ ALG ] [ LEN ] [ COMPRESSED MESSAGE ]
+ *
+ * Messages too big for one Noise message are split into fragments, sent back to back, and
+ * rebuilt by the receiver before anything else looks at them.
+ * [ OP=7 ] [ ID u32 ] [ INDEX u16 ] [ COUNT u16 ] [ PART OF MESSAGE ]
  */
 
+use byteorder::{BigEndian, ByteOrder};
 use std::convert::TryFrom;
 use std::io;
 
+/// Several Ethernet frames may share one message.
+pub const FEATURE_BATCH: u32 = 1 << 0;
+
+/// Data messages may be LZ4 compressed. Only offered when enabled for the peer on both ends.
+pub const FEATURE_LZ4: u32 = 1 << 1;
+
+/// Oversized messages may be fragmented.
+pub const FEATURE_FRAGMENT: u32 = 1 << 2;
+
+/// The server advertises its view of the tunnel MTU.
+pub const FEATURE_MTU: u32 = 1 << 3;
+
+/// Transport keys are rotated periodically.
+pub const FEATURE_REKEY: u32 = 1 << 4;
+
+/// Every optional feature this build understands.
+pub const SUPPORTED_FEATURES: u32 =
+    FEATURE_BATCH | FEATURE_LZ4 | FEATURE_FRAGMENT | FEATURE_MTU | FEATURE_REKEY;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     Control = 0,
     Ethernet = 1,
     IP = 2,
+    Disconnect = 4,
+    EthernetBatch = 5,
+    Compressed = 6,
+    Fragment = 7,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -31,7 +89,11 @@ impl TryFrom<u8> for OpCode {
             0 => Ok(OpCode::Control),
             1 => Ok(OpCode::Ethernet),
             2 => Ok(OpCode::IP),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid OpCode")),
+            4 => Ok(OpCode::Disconnect),
+            5 => Ok(OpCode::EthernetBatch),
+            6 => Ok(OpCode::Compressed),
+            7 => Ok(OpCode::Fragment),
+            _ => Err(io::Error::other("Invalid OpCode")),
         }
     }
 }
@@ -42,6 +104,9 @@ pub enum ControlType {
     Handshake = 0,
     AssignMac = 1,
     Pong = 2,
+    Mtu = 3,
+    Rekey = 4,
+    ServerKey = 5,
 }
 
 impl TryFrom<u8> for ControlType {
@@ -52,7 +117,37 @@ impl TryFrom<u8> for ControlType {
             0 => Ok(ControlType::Handshake),
             1 => Ok(ControlType::AssignMac),
             2 => Ok(ControlType::Pong),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid OpCode")),
+            3 => Ok(ControlType::Mtu),
+            4 => Ok(ControlType::Rekey),
+            5 => Ok(ControlType::ServerKey),
+            _ => Err(io::Error::other("Invalid OpCode")),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Other = 0,
+    Shutdown = 1,
+    IdleTimeout = 2,
+    LifetimeExceeded = 3,
+    /// The same peer connected again and this session made way for it.
+    Replaced = 4,
+    /// The peer was removed, disabled or expired.
+    Revoked = 5,
+}
+
+impl From<u8> for DisconnectReason {
+    // Reasons we don't know yet are still a disconnect.
+    fn from(value: u8) -> Self {
+        match value {
+            1 => DisconnectReason::Shutdown,
+            2 => DisconnectReason::IdleTimeout,
+            3 => DisconnectReason::LifetimeExceeded,
+            4 => DisconnectReason::Replaced,
+            5 => DisconnectReason::Revoked,
+            _ => DisconnectReason::Other,
         }
     }
 }
@@ -72,18 +167,15 @@ pub fn frame_ip(data: &[u8]) -> Vec<u8> {
 }
 
 pub fn classify_frame(data: &[u8]) -> io::Result<OpCode> {
-    if data.len() < 1 {
-        return Err(io::Error::new(io::ErrorKind::Other, "No data"));
+    if data.is_empty() {
+        return Err(io::Error::other("No data"));
     }
     OpCode::try_from(data[0])
 }
 
 pub fn parse_control_frame(data: &[u8]) -> io::Result<(ControlType, &[u8])> {
     if data.len() < 2 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Control frame too short",
-        ));
+        return Err(io::Error::other("Control frame too short"));
     }
 
     let ctrl_type = ControlType::try_from(data[1])?;
@@ -99,3 +191,182 @@ pub fn frame_control(ctrl: ControlType, data: &[u8]) -> Vec<u8> {
     msg.extend_from_slice(data);
     msg
 }
+
+pub fn frame_ethernet_batch<T: AsRef<[u8]>>(frames: &[T]) -> Vec<u8> {
+    let size: usize = frames.iter().map(|f| f.as_ref().len() + 2).sum();
+
+    let mut msg = Vec::with_capacity(1 + size);
+    msg.push(OpCode::EthernetBatch as u8);
+    for frame in frames {
+        let frame = frame.as_ref();
+        msg.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+        msg.extend_from_slice(frame);
+    }
+    msg
+}
+
+pub fn parse_ethernet_batch(data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut frames = Vec::new();
+    let mut rest = &data[1..];
+
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err(io::Error::other("Truncated batch length"));
+        }
+        let len = BigEndian::read_u16(rest) as usize;
+        rest = &rest[2..];
+
+        if rest.len() < len {
+            return Err(io::Error::other("Truncated batch frame"));
+        }
+        frames.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    Ok(frames)
+}
+
+/// Wraps frames into messages of at most `max_len` bytes. Without batching
+/// every frame gets its own message.
+pub fn pack_ethernet<T: AsRef<[u8]>>(frames: &[T], batching: bool, max_len: usize) -> Vec<Vec<u8>> {
+    if !batching {
+        return frames.iter().map(|f| frame_ethernet(f.as_ref())).collect();
+    }
+
+    let mut msgs = Vec::new();
+    let mut group: Vec<&[u8]> = Vec::new();
+    let mut size = 1;
+
+    for frame in frames {
+        let frame = frame.as_ref();
+        if !group.is_empty() && size + 2 + frame.len() > max_len {
+            msgs.push(pack_group(&group));
+            group.clear();
+            size = 1;
+        }
+        group.push(frame);
+        size += 2 + frame.len();
+    }
+
+    if !group.is_empty() {
+        msgs.push(pack_group(&group));
+    }
+
+    msgs
+}
+
+fn pack_group(group: &[&[u8]]) -> Vec<u8> {
+    // A lone frame is cheaper as a plain Ethernet message.
+    match group {
+        [frame] => frame_ethernet(frame),
+        _ => frame_ethernet_batch(group),
+    }
+}
+
+pub fn frame_handshake(features: u32) -> Vec<u8> {
+    frame_control(ControlType::Handshake, &features.to_be_bytes())
+}
+
+pub fn parse_handshake(payload: &[u8]) -> io::Result<u32> {
+    if payload.len() < 4 {
+        return Err(io::Error::other("Handshake too short"));
+    }
+    Ok(BigEndian::read_u32(payload))
+}
+
+pub fn frame_mtu(mtu: u16) -> Vec<u8> {
+    frame_control(ControlType::Mtu, &mtu.to_be_bytes())
+}
+
+pub fn parse_mtu(payload: &[u8]) -> io::Result<i32> {
+    if payload.len() < 2 {
+        return Err(io::Error::other("Mtu too short"));
+    }
+    Ok(BigEndian::read_u16(payload) as i32)
+}
+
+pub fn frame_server_key(key: &[u8]) -> Vec<u8> {
+    frame_control(ControlType::ServerKey, key)
+}
+
+pub fn parse_server_key(payload: &[u8]) -> io::Result<&[u8]> {
+    if payload.len() != 32 {
+        return Err(io::Error::other("Server key must be 32 bytes"));
+    }
+    Ok(payload)
+}
+
+pub fn frame_rekey() -> Vec<u8> {
+    frame_control(ControlType::Rekey, &[])
+}
+
+pub fn is_rekey(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == OpCode::Control as u8 && data[1] == ControlType::Rekey as u8
+}
+
+pub fn frame_disconnect(reason: DisconnectReason, message: &str) -> Vec<u8> {
+    let mut msg = vec![OpCode::Disconnect as u8, reason as u8];
+    msg.extend_from_slice(message.as_bytes());
+    msg
+}
+
+pub fn parse_disconnect(data: &[u8]) -> io::Result<(DisconnectReason, String)> {
+    if data.len() < 2 {
+        return Err(io::Error::other("Disconnect too short"));
+    }
+    let message = String::from_utf8_lossy(&data[2..]).into_owned();
+    Ok((DisconnectReason::from(data[1]), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unpacks a message from `pack_ethernet` back into frames.
+    fn unpack(msg: &[u8]) -> Vec<&[u8]> {
+        match classify_frame(msg).unwrap() {
+            OpCode::Ethernet => vec![&msg[1..]],
+            OpCode::EthernetBatch => parse_ethernet_batch(msg).unwrap(),
+            op => panic!("Unexpected {:?}", op),
+        }
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let frames: [&[u8]; 3] = [b"first", b"", &[0xab; 300]];
+        let msgs = pack_ethernet(&frames, true, 1400);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0][0], OpCode::EthernetBatch as u8);
+        assert_eq!(unpack(&msgs[0]), frames);
+    }
+
+    #[test]
+    fn batches_split_at_max_len() {
+        let frames = vec![vec![7u8; 100]; 5];
+        // Opcode plus two length-prefixed frames is 205 bytes.
+        let msgs = pack_ethernet(&frames, true, 205);
+        assert_eq!(msgs.len(), 3);
+        assert!(msgs.iter().all(|m| m.len() <= 205));
+        // The leftover frame goes out as a plain Ethernet message.
+        assert_eq!(msgs[2][0], OpCode::Ethernet as u8);
+
+        let unpacked: Vec<&[u8]> = msgs.iter().flat_map(|m| unpack(m)).collect();
+        assert_eq!(unpacked, frames);
+    }
+
+    #[test]
+    fn frames_go_alone_without_batching() {
+        let frames: [&[u8]; 2] = [b"one", b"two"];
+        let msgs = pack_ethernet(&frames, false, 1400);
+        assert_eq!(msgs, [frame_ethernet(b"one"), frame_ethernet(b"two")]);
+    }
+
+    #[test]
+    fn truncated_batches_are_refused() {
+        let batch = frame_ethernet_batch(&[&b"abc"[..], b"defg"]);
+        // Half a length prefix...
+        assert!(parse_ethernet_batch(&batch[..batch.len() - 5]).is_err());
+        // ...or a length longer than what is left.
+        assert!(parse_ethernet_batch(&batch[..batch.len() - 1]).is_err());
+    }
+}
@@ -1,3 +1,8 @@
 pub mod auth;
+pub mod compress;
+pub mod conf;
+pub mod fragment;
 pub mod framing;
+pub mod mtu;
 pub mod noise;
+pub mod signals;
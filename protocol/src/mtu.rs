@@ -0,0 +1,139 @@
+use std::io;
+use std::net::TcpStream;
+use std::str::FromStr;
+
+/// Used when the path MTU can't be determined.
+pub const DEFAULT_TUNNEL_MTU: i32 = 1400;
+
+// IPv6 needs at least 1280, keep a little headroom under the largest Noise message.
+const MIN_TUNNEL_MTU: i32 = 1280;
+/// The most `auto` sizes the tunnel MTU to.
+pub const MAX_TUNNEL_MTU: i32 = 65000;
+
+// An Ethernet header plus an 802.1Q tag.
+const FRAME_OVERHEAD: usize = 18;
+
+/// The largest frame a TAP device with MTU `mtu` can hand us.
+pub fn max_frame_len(mtu: i32) -> usize {
+    mtu.max(0) as usize + FRAME_OVERHEAD
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtuSetting {
+    Auto,
+    Fixed(i32),
+}
+
+impl FromStr for MtuSetting {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        if s == "auto" {
+            return Ok(MtuSetting::Auto);
+        }
+        let mtu = s
+            .parse()
+            .map_err(|_| io::Error::other(format!("Invalid MTU `{}`", s)))?;
+        check_mtu(mtu).map(MtuSetting::Fixed)
+    }
+}
+
+/// Checks a configured MTU is one the tunnel can carry and advertise.
+pub fn check_mtu(mtu: i32) -> io::Result<i32> {
+    if !(MIN_TUNNEL_MTU..=MAX_TUNNEL_MTU).contains(&mtu) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "MTU {} is outside {} to {}",
+                mtu, MIN_TUNNEL_MTU, MAX_TUNNEL_MTU
+            ),
+        ));
+    }
+    Ok(mtu)
+}
+
+/// The largest inner Ethernet payload that fits in one packet on a path with
+/// MTU `path_mtu`, after IP, TCP (with the timestamp option Linux always sends),
+/// length prefix, AEAD tag, opcode and the inner Ethernet header.
+pub fn tunnel_mtu(path_mtu: u32, ipv6: bool) -> i32 {
+    let ip = if ipv6 { 40 } else { 20 };
+    let overhead = ip + 32 + 2 + 16 + 1 + 14;
+
+    (path_mtu as i32 - overhead).clamp(MIN_TUNNEL_MTU, MAX_TUNNEL_MTU)
+}
+
+/// The tunnel MTU for an established TCP connection, based on the kernel's path MTU.
+pub fn tcp_tunnel_mtu(stream: &TcpStream) -> io::Result<i32> {
+    let ipv6 = stream.peer_addr()?.is_ipv6();
+    let path = path_mtu(stream, ipv6)?;
+    Ok(tunnel_mtu(path, ipv6))
+}
+
+#[cfg(target_os = "linux")]
+fn path_mtu(sock: &impl std::os::unix::io::AsRawFd, ipv6: bool) -> io::Result<u32> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU)
+    };
+    sys::getsockopt_int(sock.as_raw_fd(), level, name).map(|v| v as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu<T>(_sock: &T, _ipv6: bool) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Path MTU is not available on this OS",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    pub fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<i32> {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtu_settings_are_range_checked() {
+        assert_eq!("auto".parse::<MtuSetting>().unwrap(), MtuSetting::Auto);
+        assert_eq!(
+            "1400".parse::<MtuSetting>().unwrap(),
+            MtuSetting::Fixed(1400)
+        );
+        for bad in ["70000", "-1", "0", "1279", "big"] {
+            assert!(bad.parse::<MtuSetting>().is_err(), "{}", bad);
+        }
+        assert!(check_mtu(MAX_TUNNEL_MTU).is_ok());
+        assert!(check_mtu(MAX_TUNNEL_MTU + 1).is_err());
+    }
+
+    #[test]
+    fn tunnel_mtu_stays_in_range() {
+        assert_eq!(tunnel_mtu(1500, false), 1500 - 85);
+        assert_eq!(tunnel_mtu(1500, true), 1500 - 105);
+        assert_eq!(tunnel_mtu(576, false), MIN_TUNNEL_MTU);
+        assert_eq!(tunnel_mtu(u32::MAX >> 1, false), MAX_TUNNEL_MTU);
+    }
+}
@@ -1,3 +1,7 @@
 pub mod client;
+pub mod enrol;
+pub mod guard;
+pub mod params;
 pub mod server;
+pub mod session;
 pub mod util;
@@ -1,35 +1,95 @@
-use crate::noise::util::{NOISE_PARAMS, read_msg, write_msg};
+use crate::noise::guard::{MAC_LEN, mac1, mac2, tai64n_now};
+use crate::noise::params::{PRELUDE_ACCEPTED, PRELUDE_COOKIE, PSK_LOCATION, Pattern, Suite};
+use crate::noise::util::{read_msg, write_msg};
 use snow::{Builder, Keypair, TransportState};
 use std::io;
 use std::net::TcpStream;
 
+/// Runs the initiator side of the session handshake. `credential` is only needed by
+/// clients the server knows through a CA rather than a peer file.
 pub fn client_handshake(
     stream: &mut TcpStream,
     client_static: &Keypair,
     server_pub: &[u8],
+    psk: Option<&[u8]>,
+    credential: Option<&[u8]>,
+    suite: Suite,
 ) -> io::Result<TransportState> {
-    let builder = Builder::new(NOISE_PARAMS.parse().unwrap())
+    let psk: Option<&[u8; 32]> = psk
+        .map(|p| p.try_into().map_err(io::Error::other))
+        .transpose()?;
+
+    let pattern = if psk.is_some() {
+        Pattern::IKpsk2
+    } else {
+        Pattern::IK
+    };
+
+    let mut in_buf = [0u8; 65535];
+    let mut out_buf = [0u8; 65535];
+
+    let cookie = announce(stream, suite, pattern)?;
+
+    let mut builder = Builder::new(suite.params(pattern))
         .local_private_key(&client_static.private)
-        .map_err(|e| io::Error::other(e))?
+        .map_err(io::Error::other)?
         .remote_public_key(server_pub)
-        .map_err(|e| io::Error::other(e))?;
+        .map_err(io::Error::other)?;
+    if let Some(psk) = psk {
+        builder = builder.psk(PSK_LOCATION, psk).map_err(io::Error::other)?;
+    }
 
     let mut noise = builder.build_initiator().unwrap();
 
-    let mut in_buf = [0u8; 65535];
-    let mut out_buf = [0u8; 65535];
+    // Our public key, a timestamp the server can check for replays and maybe a credential.
+    let mut payload = client_static.public.clone();
+    payload.extend_from_slice(&tai64n_now());
+    payload.extend_from_slice(credential.unwrap_or_default());
 
     let client_msg_len = noise
-        .write_message(&client_static.public, &mut out_buf)
-        .map_err(|e| io::Error::other(e))?;
-    write_msg(stream, &mut out_buf[..client_msg_len])?;
+        .write_message(&payload, &mut out_buf)
+        .map_err(io::Error::other)?;
+
+    let mut msg = out_buf[..client_msg_len].to_vec();
+    let mac1 = mac1(server_pub, &msg);
+    let mac2 = match &cookie {
+        Some(cookie) => mac2(cookie, &msg, &mac1),
+        None => [0u8; MAC_LEN],
+    };
+    msg.extend_from_slice(&mac1);
+    msg.extend_from_slice(&mac2);
+    write_msg(stream, &msg)?;
 
     let server_msg_len = read_msg(stream, &mut in_buf)?;
     noise
         .read_message(&in_buf[..server_msg_len], &mut out_buf)
-        .map_err(|e| io::Error::other(e))?;
+        .map_err(io::Error::other)?;
 
     let transport = noise.into_transport_mode().unwrap();
 
     Ok(transport)
 }
+
+/// Tells the server exactly which protocol we speak, so a mismatch gets a real answer instead
+/// of a failed decryption. Returns the cookie a busy server hands out, if any.
+pub(crate) fn announce(
+    stream: &mut TcpStream,
+    suite: Suite,
+    pattern: Pattern,
+) -> io::Result<Option<Vec<u8>>> {
+    let name = suite.protocol_name(pattern);
+    write_msg(stream, name.as_bytes())?;
+
+    let mut in_buf = [0u8; 65535];
+    let reply_len = read_msg(stream, &mut in_buf)?;
+    match in_buf[..reply_len].split_first() {
+        Some((&PRELUDE_ACCEPTED, _)) => Ok(None),
+        Some((&PRELUDE_COOKIE, cookie)) => Ok(Some(cookie.to_vec())),
+        Some((_, reason)) => Err(io::Error::other(format!(
+            "Server rejected {}: {}",
+            name,
+            String::from_utf8_lossy(reason)
+        ))),
+        None => Err(io::Error::other("Empty reply to protocol announcement")),
+    }
+}
@@ -0,0 +1,123 @@
+/* Enrolment lets a client that only holds a one-time token join without any keys being copied
+ * around by hand. The client announces Noise_XX_..., both sides run XX, and the client's last
+ * handshake message carries the token. The server answers with a single encrypted message:
+ * [ STATUS ] [ NAME or REASON ]
+ *
+ * On success the server has stored the client's key under NAME, and the client pins the server
+ * key it learned during the handshake (trust on first use).
+ */
+
+use crate::noise::client::announce;
+use crate::noise::params::{Pattern, Suite};
+use crate::noise::server::{Hello, responder};
+use crate::noise::util::{decrypt, encrypt, read_msg, write_msg};
+use snow::{Builder, Keypair};
+use std::io;
+use std::net::TcpStream;
+
+const ENROL_OK: u8 = 0;
+const ENROL_FAILED: u8 = 1;
+
+/// Runs the responder side of an enrolment. `redeem` is handed the client's
+/// key and token, and returns the name the key was stored under.
+pub fn server_enrol(
+    stream: &mut TcpStream,
+    hello: Hello,
+    server_static: &Keypair,
+    redeem: impl FnOnce(&[u8], &str) -> io::Result<String>,
+) -> io::Result<String> {
+    let mut in_buf = [0u8; 65535];
+    let mut out_buf = [0u8; 65535];
+
+    let mut noise = responder(hello.suite.params(Pattern::XX), server_static)?;
+    noise
+        .read_message(&hello.message, &mut out_buf)
+        .map_err(io::Error::other)?;
+
+    let len = noise
+        .write_message(&[], &mut out_buf)
+        .map_err(io::Error::other)?;
+    write_msg(stream, &out_buf[..len])?;
+
+    let len = read_msg(stream, &mut in_buf)?;
+    let payload_len = noise
+        .read_message(&in_buf[..len], &mut out_buf)
+        .map_err(io::Error::other)?;
+    let token = String::from_utf8_lossy(&out_buf[..payload_len]).into_owned();
+
+    let client_static = noise
+        .get_remote_static()
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| io::Error::other("Client sent no static key"))?;
+    let mut transport = noise.into_transport_mode().map_err(io::Error::other)?;
+
+    let result = redeem(&client_static, token.trim());
+
+    let mut reply = Vec::new();
+    match &result {
+        Ok(name) => {
+            reply.push(ENROL_OK);
+            reply.extend_from_slice(name.as_bytes());
+        }
+        Err(e) => {
+            reply.push(ENROL_FAILED);
+            reply.extend_from_slice(e.to_string().as_bytes());
+        }
+    }
+    write_msg(stream, &encrypt(&mut transport, &reply)?)?;
+
+    result
+}
+
+/// Enrols with a server we don't know yet, returning the server's public key
+/// and the name we were enrolled under.
+pub fn client_enrol(
+    stream: &mut TcpStream,
+    client_static: &Keypair,
+    suite: Suite,
+    token: &str,
+) -> io::Result<(Vec<u8>, String)> {
+    let mut in_buf = [0u8; 65535];
+    let mut out_buf = [0u8; 65535];
+
+    announce(stream, suite, Pattern::XX)?;
+
+    let mut noise = Builder::new(suite.params(Pattern::XX))
+        .local_private_key(&client_static.private)
+        .map_err(io::Error::other)?
+        .build_initiator()
+        .map_err(io::Error::other)?;
+
+    let len = noise
+        .write_message(&[], &mut out_buf)
+        .map_err(io::Error::other)?;
+    write_msg(stream, &out_buf[..len])?;
+
+    let len = read_msg(stream, &mut in_buf)?;
+    noise
+        .read_message(&in_buf[..len], &mut out_buf)
+        .map_err(io::Error::other)?;
+
+    // The token only goes out once it is encrypted to the server's key.
+    let len = noise
+        .write_message(token.as_bytes(), &mut out_buf)
+        .map_err(io::Error::other)?;
+    write_msg(stream, &out_buf[..len])?;
+
+    let server_static = noise
+        .get_remote_static()
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| io::Error::other("Server sent no static key"))?;
+    let mut transport = noise.into_transport_mode().map_err(io::Error::other)?;
+
+    let len = read_msg(stream, &mut in_buf)?;
+    let reply = decrypt(&mut transport, &in_buf[..len])?;
+    match reply.split_first() {
+        Some((&ENROL_OK, name)) => Ok((server_static, String::from_utf8_lossy(name).into_owned())),
+        Some((_, reason)) => Err(io::Error::other(format!(
+            "Enrolment refused: {}",
+            String::from_utf8_lossy(reason)
+        ))),
+        None => Err(io::Error::other("Empty enrolment reply")),
+    }
+}
@@ -0,0 +1,291 @@
+/* Handshake hardening, modelled on WireGuard:
+ *
+ * The client's first handshake message carries a TAI64N timestamp after its public key, and the
+ * server only accepts a timestamp newer than the last one it saw from that peer, so a captured
+ * message can't be replayed.
+ * Payload -> [ PUBLIC KEY 32 ] [ TAI64N 12 ]
+ *
+ * Clients that announce their protocol also append two MACs to that message. MAC1 is keyed with
+ * the server's public key, so the server can drop junk without doing any DH. MAC2 is keyed with
+ * a cookie the server only hands out when it is busy: the cookie is tied to the client address
+ * and a secret that changes every couple of minutes.
+ * [ NOISE MESSAGE ] [ MAC1 16 ] [ MAC2 16 ]
+ */
+
+use blake2::digest::consts::U16;
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2s256, Blake2sMac, Digest};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const TIMESTAMP_LEN: usize = 12;
+pub const MAC_LEN: usize = 16;
+pub const COOKIE_LEN: usize = 16;
+
+const MAC1_LABEL: &[u8] = b"mac1----";
+const COOKIE_SECRET_LIFETIME: Duration = Duration::from_secs(120);
+
+pub type Tai64N = [u8; TIMESTAMP_LEN];
+
+pub fn tai64n_now() -> Tai64N {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut ts = [0u8; TIMESTAMP_LEN];
+    ts[..8].copy_from_slice(&((1u64 << 62) + now.as_secs()).to_be_bytes());
+    ts[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    ts
+}
+
+fn keyed_mac(key: &[u8], parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// MAC1 over a handshake message, keyed with the responder's public key.
+pub fn mac1(server_pub: &[u8], msg: &[u8]) -> [u8; MAC_LEN] {
+    let key = Blake2s256::new()
+        .chain_update(MAC1_LABEL)
+        .chain_update(server_pub)
+        .finalize();
+    keyed_mac(&key, &[msg])
+}
+
+/// MAC2 over a handshake message and its MAC1, keyed with a cookie.
+pub fn mac2(cookie: &[u8], msg: &[u8], mac1: &[u8]) -> [u8; MAC_LEN] {
+    keyed_mac(cookie, &[msg, mac1])
+}
+
+struct CookieSecret {
+    secret: [u8; 32],
+    created: Instant,
+}
+
+/// Server side state shared by every handshake in progress.
+pub struct HandshakeGuard {
+    timestamps: Mutex<HashMap<Vec<u8>, Tai64N>>,
+    cookie_secret: Mutex<CookieSecret>,
+    pending: Arc<AtomicUsize>,
+    cookie_threshold: AtomicUsize,
+    allow_legacy: AtomicBool,
+}
+
+/// Counts a handshake as pending until dropped.
+pub struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HandshakeGuard {
+    /// Above `cookie_threshold` pending handshakes, clients must present a cookie.
+    /// `allow_legacy` lets in clients that send no timestamp or MACs.
+    pub fn new(cookie_threshold: usize, allow_legacy: bool) -> Self {
+        Self {
+            timestamps: Mutex::new(HashMap::new()),
+            cookie_secret: Mutex::new(CookieSecret {
+                secret: random_secret(),
+                created: Instant::now(),
+            }),
+            pending: Arc::new(AtomicUsize::new(0)),
+            cookie_threshold: AtomicUsize::new(cookie_threshold),
+            allow_legacy: AtomicBool::new(allow_legacy),
+        }
+    }
+
+    /// Changes the settings given to `new`, e.g. after the config was reloaded.
+    pub fn configure(&self, cookie_threshold: usize, allow_legacy: bool) {
+        self.cookie_threshold
+            .store(cookie_threshold, Ordering::Relaxed);
+        self.allow_legacy.store(allow_legacy, Ordering::Relaxed);
+    }
+
+    pub fn begin(&self) -> Pending {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        Pending(Arc::clone(&self.pending))
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn under_load(&self) -> bool {
+        self.pending() > self.cookie_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn allow_legacy(&self) -> bool {
+        self.allow_legacy.load(Ordering::Relaxed)
+    }
+
+    pub fn cookie(&self, addr: IpAddr) -> [u8; COOKIE_LEN] {
+        let mut state = self.cookie_secret.lock().unwrap();
+        if state.created.elapsed() >= COOKIE_SECRET_LIFETIME {
+            state.secret = random_secret();
+            state.created = Instant::now();
+        }
+
+        let ip = match addr {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        keyed_mac(&state.secret, &[&ip])
+    }
+
+    /// Records a peer's handshake timestamp, refusing anything not newer than the last one.
+    /// Peers that have sent a timestamp can't fall back to legacy handshakes without one.
+    pub fn check_timestamp(&self, key: &[u8], timestamp: Option<Tai64N>) -> io::Result<()> {
+        let mut timestamps = self.timestamps.lock().unwrap();
+        let Some(timestamp) = timestamp else {
+            if !self.allow_legacy() {
+                return Err(io::Error::other("Handshake has no timestamp"));
+            }
+            if timestamps.contains_key(key) {
+                return Err(io::Error::other(
+                    "Legacy handshake from a peer that sends timestamps",
+                ));
+            }
+            return Ok(());
+        };
+
+        if timestamps.get(key).is_some_and(|last| timestamp <= *last) {
+            return Err(io::Error::other("Replayed handshake"));
+        }
+        timestamps.insert(key.to_vec(), timestamp);
+        Ok(())
+    }
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tai64n(secs: u64, nanos: u32) -> Tai64N {
+        let mut ts = [0u8; TIMESTAMP_LEN];
+        ts[..8].copy_from_slice(&((1u64 << 62) + secs).to_be_bytes());
+        ts[8..].copy_from_slice(&nanos.to_be_bytes());
+        ts
+    }
+
+    #[test]
+    fn timestamps_must_move_forward() {
+        let guard = HandshakeGuard::new(0, false);
+        guard
+            .check_timestamp(b"alice", Some(tai64n(100, 5)))
+            .unwrap();
+        assert!(
+            guard
+                .check_timestamp(b"alice", Some(tai64n(100, 5)))
+                .is_err()
+        );
+        assert!(
+            guard
+                .check_timestamp(b"alice", Some(tai64n(99, 9)))
+                .is_err()
+        );
+        guard
+            .check_timestamp(b"alice", Some(tai64n(100, 6)))
+            .unwrap();
+        guard
+            .check_timestamp(b"alice", Some(tai64n(101, 0)))
+            .unwrap();
+        assert!(
+            guard
+                .check_timestamp(b"alice", Some(tai64n(100, 7)))
+                .is_err()
+        );
+
+        // Each peer has its own clock.
+        guard.check_timestamp(b"bob", Some(tai64n(1, 0))).unwrap();
+
+        // Real timestamps use the same encoding, so they sort the same way.
+        assert!(tai64n_now() > tai64n(1_000_000_000, 0));
+    }
+
+    #[test]
+    fn legacy_handshakes_need_allow_legacy() {
+        let guard = HandshakeGuard::new(0, false);
+        assert!(guard.check_timestamp(b"alice", None).is_err());
+
+        guard.configure(0, true);
+        assert!(guard.allow_legacy());
+        guard.check_timestamp(b"alice", None).unwrap();
+
+        // Not once the peer has shown it can send a timestamp.
+        guard
+            .check_timestamp(b"alice", Some(tai64n(100, 0)))
+            .unwrap();
+        assert!(guard.check_timestamp(b"alice", None).is_err());
+        guard.check_timestamp(b"bob", None).unwrap();
+    }
+
+    #[test]
+    fn cookies_are_needed_above_the_pending_threshold() {
+        let guard = HandshakeGuard::new(1, false);
+        let first = guard.begin();
+        assert!(!guard.under_load());
+        let second = guard.begin();
+        assert_eq!(guard.pending(), 2);
+        assert!(guard.under_load());
+
+        drop(first);
+        assert!(!guard.under_load());
+        guard.configure(0, false);
+        assert!(guard.under_load());
+        drop(second);
+        assert_eq!(guard.pending(), 0);
+        assert!(!guard.under_load());
+    }
+
+    #[test]
+    fn cookies_are_tied_to_the_client_address() {
+        let guard = HandshakeGuard::new(0, false);
+        let here: IpAddr = "192.0.2.1".parse().unwrap();
+        let there: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(guard.cookie(here), guard.cookie(here));
+        assert_ne!(guard.cookie(here), guard.cookie(there));
+
+        // Another server (or a restarted one) has a different secret.
+        assert_ne!(
+            guard.cookie(here),
+            HandshakeGuard::new(0, false).cookie(here)
+        );
+
+        // Once the secret expires, old cookies stop matching.
+        let before = guard.cookie(here);
+        guard.cookie_secret.lock().unwrap().created -= COOKIE_SECRET_LIFETIME;
+        assert_ne!(guard.cookie(here), before);
+    }
+
+    #[test]
+    fn macs_cover_the_key_message_and_cookie() {
+        let msg = b"handshake message";
+        let mac = mac1(b"server key", msg);
+        assert_eq!(mac, mac1(b"server key", msg));
+        assert_ne!(mac, mac1(b"other key", msg));
+        assert_ne!(mac, mac1(b"server key", b"handshake massage"));
+
+        let guard = HandshakeGuard::new(0, false);
+        let cookie = guard.cookie("192.0.2.1".parse().unwrap());
+        let other = guard.cookie("192.0.2.2".parse().unwrap());
+        assert_eq!(mac2(&cookie, msg, &mac), mac2(&cookie, msg, &mac));
+        assert_ne!(mac2(&cookie, msg, &mac), mac2(&other, msg, &mac));
+        assert_ne!(mac2(&cookie, msg, &mac), mac2(&cookie, msg, &[0; MAC_LEN]));
+    }
+}
@@ -0,0 +1,215 @@
+/* Noise protocol names are only ever built from this allow-list, never taken from config or
+ * the wire as they are: Noise_<PATTERN>_25519_<CIPHER>_<HASH>. Sessions use IK, or IKpsk2 with
+ * a PSK. XX is only used to enrol clients that don't know the server key yet.
+ *
+ * Before its first handshake message the client announces the exact protocol it is about to
+ * use, and the server says whether it accepts it. A busy server answers with a cookie instead
+ * (see `guard`). Clients that don't announce anything are assumed to use the default suite.
+ * Client -> [ PROTOCOL NAME ]
+ * Server -> [ STATUS ] [ REASON or COOKIE ]
+ */
+
+use snow::params::NoiseParams;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+pub const PROTOCOL_PREFIX: &[u8] = b"Noise_";
+
+pub const PRELUDE_ACCEPTED: u8 = 0;
+pub const PRELUDE_REJECTED: u8 = 1;
+pub const PRELUDE_COOKIE: u8 = 2;
+
+/// Where IKpsk2 mixes in the pre-shared key (the server's reply).
+pub const PSK_LOCATION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    ChaChaPoly,
+    AesGcm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    Blake2s,
+    Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    IK,
+    IKpsk2,
+    XX,
+}
+
+impl Pattern {
+    const ALL: [Pattern; 3] = [Pattern::IK, Pattern::IKpsk2, Pattern::XX];
+
+    fn name(self) -> &'static str {
+        match self {
+            Pattern::IK => "IK",
+            Pattern::IKpsk2 => "IKpsk2",
+            Pattern::XX => "XX",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suite {
+    pub cipher: Cipher,
+    pub hash: Hash,
+}
+
+pub const DEFAULT_SUITE: Suite = Suite {
+    cipher: Cipher::ChaChaPoly,
+    hash: Hash::Blake2s,
+};
+
+pub const ALL_SUITES: [Suite; 4] = [
+    DEFAULT_SUITE,
+    Suite {
+        cipher: Cipher::ChaChaPoly,
+        hash: Hash::Sha256,
+    },
+    Suite {
+        cipher: Cipher::AesGcm,
+        hash: Hash::Blake2s,
+    },
+    Suite {
+        cipher: Cipher::AesGcm,
+        hash: Hash::Sha256,
+    },
+];
+
+impl Suite {
+    pub fn protocol_name(self, pattern: Pattern) -> String {
+        format!("Noise_{}_25519_{}", pattern.name(), self)
+    }
+
+    pub fn params(self, pattern: Pattern) -> NoiseParams {
+        self.protocol_name(pattern).parse().unwrap()
+    }
+
+    /// Finds the suite and pattern a protocol name refers to.
+    pub fn from_protocol_name(name: &str) -> Option<(Suite, Pattern)> {
+        ALL_SUITES
+            .iter()
+            .flat_map(|&suite| Pattern::ALL.map(|pattern| (suite, pattern)))
+            .find(|&(suite, pattern)| suite.protocol_name(pattern) == name)
+    }
+
+    /// Parses a comma separated list, e.g. `AESGCM_SHA256, ChaChaPoly_BLAKE2s`.
+    pub fn parse_list(s: &str) -> io::Result<Vec<Suite>> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cipher = match self.cipher {
+            Cipher::ChaChaPoly => "ChaChaPoly",
+            Cipher::AesGcm => "AESGCM",
+        };
+        let hash = match self.hash {
+            Hash::Blake2s => "BLAKE2s",
+            Hash::Sha256 => "SHA256",
+        };
+        write!(f, "{}_{}", cipher, hash)
+    }
+}
+
+impl FromStr for Suite {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        ALL_SUITES
+            .iter()
+            .copied()
+            .find(|suite| suite.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| io::Error::other(format!("Unknown cipher suite `{}`", s)))
+    }
+}
+
+/// Lists suites the way they are written in config.
+pub fn suite_list(suites: &[Suite]) -> String {
+    suites
+        .iter()
+        .map(Suite::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suites_parse_from_config() {
+        assert_eq!(
+            "ChaChaPoly_BLAKE2s".parse::<Suite>().unwrap(),
+            DEFAULT_SUITE
+        );
+        assert_eq!(
+            "aesgcm_sha256".parse::<Suite>().unwrap(),
+            ALL_SUITES[3],
+            "names are case insensitive"
+        );
+        assert_eq!(
+            Suite::parse_list(" AESGCM_SHA256, ChaChaPoly_BLAKE2s ,").unwrap(),
+            [ALL_SUITES[3], DEFAULT_SUITE]
+        );
+        assert_eq!(
+            suite_list(&ALL_SUITES[..2]),
+            "ChaChaPoly_BLAKE2s, ChaChaPoly_SHA256"
+        );
+        for suite in ALL_SUITES {
+            assert_eq!(suite.to_string().parse::<Suite>().unwrap(), suite);
+        }
+    }
+
+    #[test]
+    fn unknown_suites_are_refused() {
+        for bad in [
+            "",
+            "ChaChaPoly",
+            "AESGCM_BLAKE2b",
+            "ChaChaPoly_BLAKE2s_SHA256",
+        ] {
+            assert!(bad.parse::<Suite>().is_err(), "{}", bad);
+        }
+        assert!(Suite::parse_list("ChaChaPoly_BLAKE2s, Rot13_MD5").is_err());
+    }
+
+    #[test]
+    fn protocol_names_round_trip() {
+        for suite in ALL_SUITES {
+            for pattern in Pattern::ALL {
+                let name = suite.protocol_name(pattern);
+                assert_eq!(Suite::from_protocol_name(&name), Some((suite, pattern)));
+                assert_eq!(suite.params(pattern).name, name);
+            }
+        }
+        assert_eq!(
+            DEFAULT_SUITE.protocol_name(Pattern::IKpsk2),
+            "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s"
+        );
+    }
+
+    #[test]
+    fn protocols_outside_the_allow_list_are_refused() {
+        for bad in [
+            "Noise_NN_25519_ChaChaPoly_BLAKE2s",
+            "Noise_IK_448_ChaChaPoly_BLAKE2s",
+            "Noise_IK_25519_ChaChaPoly_BLAKE2b",
+            "Noise_ik_25519_chachapoly_blake2s",
+            "Noise_IK_25519_ChaChaPoly_BLAKE2s\0",
+            "",
+        ] {
+            assert_eq!(Suite::from_protocol_name(bad), None, "{}", bad);
+        }
+    }
+}
@@ -1,38 +1,359 @@
-use crate::noise::util::{NOISE_PARAMS, read_msg, write_msg};
-use snow::{Builder, Keypair, TransportState};
+use crate::auth::keyfile::StaticKeypair;
+use crate::auth::peer::Peer;
+use crate::noise::guard::{HandshakeGuard, MAC_LEN, TIMESTAMP_LEN, Tai64N, mac1, mac2};
+use crate::noise::params::{
+    DEFAULT_SUITE, PRELUDE_ACCEPTED, PRELUDE_COOKIE, PRELUDE_REJECTED, PROTOCOL_PREFIX,
+    PSK_LOCATION, Pattern, Suite, suite_list,
+};
+use crate::noise::util::{read_msg, write_msg};
+use snow::params::NoiseParams;
+use snow::{Builder, HandshakeState, Keypair, TransportState};
 use std::io;
 use std::net::TcpStream;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
 
-pub fn server_handshake(
-    stream: &mut TcpStream,
-    server_static: &Keypair,
-) -> io::Result<(TransportState, Vec<u8>)> {
-    // Build Noise Responder
-    let builder = Builder::new(NOISE_PARAMS.parse().unwrap())
-        .local_private_key(&server_static.private)
-        .map_err(|e| io::Error::other(e))?;
+/// A client's opening message, read before any DH has been done.
+pub struct Hello {
+    pub suite: Suite,
+    /// `None` for older clients that don't announce a protocol.
+    pub pattern: Option<Pattern>,
+    /// Which of the server's static keys the client's MACs were made with.
+    pub(crate) server_static: usize,
+    pub(crate) message: Vec<u8>,
+}
 
-    let mut noise = builder.build_responder().unwrap();
+impl Hello {
+    pub fn is_enrolment(&self) -> bool {
+        self.pattern == Some(Pattern::XX)
+    }
+}
 
+/// Reads the client's protocol announcement (if it sends one) and first handshake message,
+/// accepting any of `suites`. Under load, clients have to echo back a cookie first.
+/// `server_statics` are the keys clients may know us by, the current one first.
+pub fn read_hello(
+    stream: &mut TcpStream,
+    server_statics: &[Arc<StaticKeypair>],
+    suites: &[Suite],
+    guard: &HandshakeGuard,
+) -> io::Result<Hello> {
     let mut in_buf = [0u8; 65535];
-    let mut out_buf = [0u8; 65535];
 
     // Receive message from client.
-    let client_message_len = read_msg(stream, &mut in_buf)?;
-    let payload_len = noise
-        .read_message(&in_buf[..client_message_len], &mut out_buf)
-        .map_err(|e| io::Error::other(e))?;
+    let len = read_msg(stream, &mut in_buf)?;
+
+    if !in_buf[..len].starts_with(PROTOCOL_PREFIX) {
+        if !guard.allow_legacy() {
+            return Err(io::Error::other("Client didn't announce a protocol"));
+        }
+        if guard.under_load() {
+            return Err(io::Error::other("Too busy for a legacy handshake"));
+        }
+
+        // Older clients don't announce anything and can only speak the default suite.
+        if !suites.contains(&DEFAULT_SUITE) {
+            return Err(io::Error::other(format!(
+                "Client didn't announce a protocol and {} is not allowed",
+                DEFAULT_SUITE
+            )));
+        }
+
+        return Ok(Hello {
+            suite: DEFAULT_SUITE,
+            pattern: None,
+            server_static: 0,
+            message: in_buf[..len].to_vec(),
+        });
+    }
+
+    let name = String::from_utf8_lossy(&in_buf[..len]).into_owned();
+    let choice = Suite::from_protocol_name(&name).filter(|(suite, _)| suites.contains(suite));
+
+    let reject = |stream: &mut TcpStream, reason: String| {
+        let mut reply = vec![PRELUDE_REJECTED];
+        reply.extend_from_slice(reason.as_bytes());
+        write_msg(stream, &reply)?;
+        Err(io::Error::other(format!(
+            "Client asked for {}, {}",
+            name, reason
+        )))
+    };
+
+    let Some((suite, pattern)) = choice else {
+        return reject(stream, format!("server allows {}", suite_list(suites)));
+    };
+
+    // Enrolling clients don't know our key yet, so they can't prove anything up front.
+    if pattern == Pattern::XX && guard.under_load() {
+        return reject(stream, "server is busy, try again later".to_string());
+    }
+
+    // When busy, make the client prove it can hear us before we do any DH.
+    let cookie = if guard.under_load() {
+        let cookie = guard.cookie(stream.peer_addr()?.ip());
+        let mut reply = vec![PRELUDE_COOKIE];
+        reply.extend_from_slice(&cookie);
+        write_msg(stream, &reply)?;
+        Some(cookie)
+    } else {
+        write_msg(stream, &[PRELUDE_ACCEPTED])?;
+        None
+    };
+
+    let mut len = read_msg(stream, &mut in_buf)?;
+    let mut server_static = 0;
+    if pattern != Pattern::XX {
+        (len, server_static) = check_macs(
+            &in_buf[..len],
+            server_statics,
+            cookie.as_ref().map(|c| &c[..]),
+        )?;
+    }
+
+    Ok(Hello {
+        suite,
+        pattern: Some(pattern),
+        server_static,
+        message: in_buf[..len].to_vec(),
+    })
+}
+
+/// What the responder learned from a session handshake.
+pub struct Handshake {
+    pub transport: TransportState,
+    pub client_static: Vec<u8>,
+    /// Who `accept` decided the client is.
+    pub peer: Peer,
+    /// Which of the server's static keys the client knew us by. Anything but 0 means it
+    /// still has a key we have since rotated away from.
+    pub server_static: usize,
+}
+
+/// Runs the responder side of the session handshake. `accept` is given the client's static
+/// key, handshake timestamp and credential (see `auth::credential`) before we answer, so
+/// unknown and replayed handshakes get no response. `psk_for` returns the pre-shared key
+/// configured for a client's static key, if it has one. Clients with a PSK must use it, and
+/// clients using one must have it configured.
+pub fn server_handshake(
+    stream: &mut TcpStream,
+    hello: Hello,
+    server_statics: &[Arc<StaticKeypair>],
+    accept: impl FnOnce(&[u8], Option<Tai64N>, Option<&[u8]>) -> io::Result<Peer>,
+    psk_for: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> io::Result<Handshake> {
+    let mut out_buf = [0u8; 65535];
+    let suite = hello.suite;
+    let client_message = &hello.message[..];
+
+    let (mut noise, payload_len, used_psk, server_static) = match hello.pattern {
+        Some(Pattern::XX) => return Err(io::Error::other("Unexpected enrolment handshake")),
+        Some(pattern) => {
+            let mut noise = responder(suite.params(pattern), &server_statics[hello.server_static])?;
+            let len = noise
+                .read_message(client_message, &mut out_buf)
+                .map_err(io::Error::other)?;
+            (noise, len, pattern == Pattern::IKpsk2, hello.server_static)
+        }
+        None => {
+            // The two patterns hash differently, and so do our keys, so only the combination
+            // the client used can decrypt its static key.
+            let mut found = None;
+            'keys: for (i, server_static) in server_statics.iter().enumerate() {
+                for (pattern, used_psk) in [(Pattern::IKpsk2, true), (Pattern::IK, false)] {
+                    let mut noise = responder(suite.params(pattern), server_static)?;
+                    if let Ok(len) = noise.read_message(client_message, &mut out_buf) {
+                        found = Some((noise, len, used_psk, i));
+                        break 'keys;
+                    }
+                }
+            }
+            found.ok_or_else(|| io::Error::other("Couldn't decrypt legacy handshake"))?
+        }
+    };
+
+    // Newer clients follow their public key with a timestamp. Older ones send nothing else,
+    // so a longer legacy payload is a newer client's message stripped of its announcement and
+    // MACs, and its timestamp is checked all the same.
+    let payload = &out_buf[..payload_len];
+    let timestamp: Option<Tai64N> = if hello.pattern.is_some() || payload.len() > 32 {
+        Some(
+            payload
+                .get(32..32 + TIMESTAMP_LEN)
+                .and_then(|ts| ts.try_into().ok())
+                .ok_or_else(|| io::Error::other("Handshake has no timestamp"))?,
+        )
+    } else {
+        None
+    };
+    let credential = payload
+        .get(32 + TIMESTAMP_LEN..)
+        .filter(|rest| timestamp.is_some() && !rest.is_empty());
 
     // Extract client static key (used for authentication)
-    let client_static_pubkey = out_buf[..payload_len].to_vec();
+    let client_static_pubkey = noise
+        .get_remote_static()
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| io::Error::other("Client sent no static key"))?;
+
+    let peer = accept(&client_static_pubkey, timestamp, credential)?;
+
+    match (psk_for(&client_static_pubkey), used_psk) {
+        (Some(psk), true) => noise
+            .set_psk(PSK_LOCATION as usize, &psk)
+            .map_err(io::Error::other)?,
+        (None, false) => {}
+        (Some(_), false) => {
+            return Err(io::Error::other(
+                "Client has a pre-shared key but didn't use it",
+            ));
+        }
+        (None, true) => return Err(io::Error::other("No pre-shared key for client")),
+    }
 
     // Send server response
     let response_message_len = noise
         .write_message(&[], &mut out_buf)
-        .map_err(|e| io::Error::other(e))?;
-    write_msg(stream, &mut out_buf[..response_message_len])?;
+        .map_err(io::Error::other)?;
+    write_msg(stream, &out_buf[..response_message_len])?;
 
     let transport = noise.into_transport_mode().unwrap();
 
-    Ok((transport, client_static_pubkey))
+    Ok(Handshake {
+        transport,
+        client_static: client_static_pubkey,
+        peer,
+        server_static,
+    })
+}
+
+/// Checks the MACs trailing a client's first message, returning the length of the Noise
+/// message in front of them and which of our keys MAC1 was made with.
+fn check_macs(
+    msg: &[u8],
+    server_statics: &[Arc<StaticKeypair>],
+    cookie: Option<&[u8]>,
+) -> io::Result<(usize, usize)> {
+    let noise_len = msg
+        .len()
+        .checked_sub(2 * MAC_LEN)
+        .ok_or_else(|| io::Error::other("Handshake message too short"))?;
+    let (noise_msg, macs) = msg.split_at(noise_len);
+    let (msg_mac1, msg_mac2) = macs.split_at(MAC_LEN);
+
+    let server_static = server_statics
+        .iter()
+        .position(|key| bool::from(mac1(&key.public, noise_msg).ct_eq(msg_mac1)))
+        .ok_or_else(|| io::Error::other("Bad MAC1 on handshake"))?;
+    if let Some(cookie) = cookie
+        && !bool::from(mac2(cookie, noise_msg, msg_mac1).ct_eq(msg_mac2))
+    {
+        return Err(io::Error::other("Bad MAC2 on handshake"));
+    }
+
+    Ok((noise_len, server_static))
+}
+
+pub(crate) fn responder(
+    params: NoiseParams,
+    server_static: &Keypair,
+) -> io::Result<HandshakeState> {
+    Builder::new(params)
+        .local_private_key(&server_static.private)
+        .map_err(io::Error::other)?
+        .build_responder()
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::guard::tai64n_now;
+    use std::net::TcpListener;
+
+    fn keypair() -> Keypair {
+        Builder::new(DEFAULT_SUITE.params(Pattern::IK))
+            .generate_keypair()
+            .unwrap()
+    }
+
+    /// A client's first IK message as it goes inside the MACs, with or without a timestamp.
+    fn initiation(client: &Keypair, server_pub: &[u8], timestamp: bool) -> Vec<u8> {
+        let mut noise = Builder::new(DEFAULT_SUITE.params(Pattern::IK))
+            .local_private_key(&client.private)
+            .unwrap()
+            .remote_public_key(server_pub)
+            .unwrap()
+            .build_initiator()
+            .unwrap();
+        let mut payload = client.public.clone();
+        if timestamp {
+            payload.extend_from_slice(&tai64n_now());
+        }
+        let mut buf = [0u8; 1024];
+        let len = noise.write_message(&payload, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// Runs the responder side on `message` the way the server does, checking timestamps
+    /// with `guard`.
+    fn respond(
+        guard: &HandshakeGuard,
+        server: &Arc<StaticKeypair>,
+        pattern: Option<Pattern>,
+        message: &[u8],
+    ) -> io::Result<Handshake> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let hello = Hello {
+            suite: DEFAULT_SUITE,
+            pattern,
+            server_static: 0,
+            message: message.to_vec(),
+        };
+        server_handshake(
+            &mut stream,
+            hello,
+            std::slice::from_ref(server),
+            |key, timestamp, _| {
+                guard.check_timestamp(key, timestamp)?;
+                Ok(Peer::new("client", key.to_vec()))
+            },
+            |_| None,
+        )
+    }
+
+    #[test]
+    fn stripped_messages_cant_be_replayed_as_legacy_handshakes() {
+        let guard = HandshakeGuard::new(usize::MAX, true);
+        let server = Arc::new(StaticKeypair::from(keypair()));
+        let client = keypair();
+
+        let message = initiation(&client, &server.public, true);
+        respond(&guard, &server, Some(Pattern::IK), &message).unwrap();
+        let err = respond(&guard, &server, None, &message).err().unwrap();
+        assert_eq!(err.to_string(), "Replayed handshake");
+
+        // Nor can the same client be impersonated with an old-style message.
+        let legacy = initiation(&client, &server.public, false);
+        assert!(respond(&guard, &server, None, &legacy).is_err());
+    }
+
+    #[test]
+    fn legacy_handshakes_are_checked_when_they_carry_a_timestamp() {
+        let guard = HandshakeGuard::new(usize::MAX, true);
+        let server = Arc::new(StaticKeypair::from(keypair()));
+        let (old, new) = (keypair(), keypair());
+
+        // Clients that never sent a timestamp keep working.
+        let legacy = initiation(&old, &server.public, false);
+        respond(&guard, &server, None, &legacy).unwrap();
+        respond(&guard, &server, None, &legacy).unwrap();
+
+        let stripped = initiation(&new, &server.public, true);
+        let handshake = respond(&guard, &server, None, &stripped).unwrap();
+        assert_eq!(handshake.client_static, new.public);
+        assert!(respond(&guard, &server, None, &stripped).is_err());
+    }
 }
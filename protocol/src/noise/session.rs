@@ -0,0 +1,300 @@
+use crate::compress::{Compression, compress_message};
+use crate::fragment::fragment;
+use crate::framing::{
+    DisconnectReason, FEATURE_BATCH, FEATURE_FRAGMENT, FEATURE_REKEY, frame_disconnect,
+    frame_rekey, is_rekey, pack_ethernet,
+};
+use crate::mtu::tcp_tunnel_mtu;
+use crate::noise::util::{MAX_PLAINTEXT, decrypt, encrypt, send_ciphertext_batch};
+use snow::TransportState;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Rotate the sending key after this long...
+pub const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// ...or after this many bytes, whichever comes first.
+pub const REKEY_AFTER_BYTES: u64 = 1 << 30;
+
+/// Longest `disconnect` waits for the peer to take the reason.
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct SendState {
+    stream: TcpStream,
+    bytes_since_rekey: u64,
+    last_rekey: Instant,
+}
+
+/// An established tunnel. Messages are encrypted and written under the same
+/// lock, so they always hit the wire in nonce order no matter which thread
+/// sends them. Decryption only needs the transport lock.
+///
+/// Once both sides agree on `FEATURE_REKEY`, each side rotates its own sending
+/// key by sending a Rekey control message, and follows the peer's rotations
+/// as it decrypts them.
+pub struct Session {
+    transport: Mutex<TransportState>,
+    writer: Mutex<SendState>,
+    // A second handle so the connection can be closed while a send is stuck.
+    closer: TcpStream,
+    features: AtomicU32,
+    next_fragment_id: AtomicU32,
+    peer_mtu: AtomicI32,
+    rekeys_sent: AtomicU64,
+    rekeys_received: AtomicU64,
+}
+
+impl Session {
+    pub fn new(transport: TransportState, stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            transport: Mutex::new(transport),
+            closer: stream.try_clone()?,
+            writer: Mutex::new(SendState {
+                stream,
+                bytes_since_rekey: 0,
+                last_rekey: Instant::now(),
+            }),
+            features: AtomicU32::new(0),
+            next_fragment_id: AtomicU32::new(0),
+            peer_mtu: AtomicI32::new(0),
+            rekeys_sent: AtomicU64::new(0),
+            rekeys_received: AtomicU64::new(0),
+        })
+    }
+
+    pub fn send(&self, plaintext: &[u8]) -> io::Result<()> {
+        self.send_batch(&[plaintext])
+    }
+
+    pub fn send_batch<T: AsRef<[u8]>>(&self, plaintexts: &[T]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.send_locked(&mut writer, plaintexts)
+    }
+
+    fn send_locked<T: AsRef<[u8]>>(
+        &self,
+        writer: &mut SendState,
+        plaintexts: &[T],
+    ) -> io::Result<()> {
+        let ciphertexts = {
+            let mut transport = self.transport.lock().unwrap();
+            let mut ciphertexts = plaintexts
+                .iter()
+                .map(|p| encrypt(&mut transport, p.as_ref()))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            writer.bytes_since_rekey += plaintexts
+                .iter()
+                .map(|p| p.as_ref().len() as u64)
+                .sum::<u64>();
+
+            // The Rekey message still goes out under the old key, right behind this batch.
+            if self.rekey_due(writer) {
+                ciphertexts.push(encrypt(&mut transport, &frame_rekey())?);
+                transport.rekey_outgoing();
+                writer.bytes_since_rekey = 0;
+                writer.last_rekey = Instant::now();
+                self.rekeys_sent.fetch_add(1, Ordering::Relaxed);
+            }
+
+            ciphertexts
+        };
+
+        send_ciphertext_batch(&mut writer.stream, &ciphertexts)
+    }
+
+    fn rekey_due(&self, state: &SendState) -> bool {
+        self.has_feature(FEATURE_REKEY)
+            && (state.bytes_since_rekey >= REKEY_AFTER_BYTES
+                || state.last_rekey.elapsed() >= REKEY_AFTER_TIME)
+    }
+
+    /// Sends Ethernet frames using whatever batching and compression was negotiated.
+    pub fn send_frames<T: AsRef<[u8]>>(&self, frames: &[T]) -> io::Result<()> {
+        let compression = Compression::from_features(self.features());
+        let mut msgs = Vec::new();
+
+        for msg in pack_ethernet(frames, self.has_feature(FEATURE_BATCH), MAX_PLAINTEXT) {
+            let msg = compress_message(msg, compression);
+            if msg.len() <= MAX_PLAINTEXT {
+                msgs.push(msg);
+            } else {
+                msgs.extend(self.fragment(&msg)?);
+            }
+        }
+
+        self.send_batch(&msgs)
+    }
+
+    fn fragment(&self, msg: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        if !self.has_feature(FEATURE_FRAGMENT) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Frame too large and the peer can't reassemble fragments",
+            ));
+        }
+
+        let id = self.next_fragment_id.fetch_add(1, Ordering::Relaxed);
+        fragment(msg, id, MAX_PLAINTEXT)
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut transport = self.transport.lock().unwrap();
+        let plaintext = decrypt(&mut transport, ciphertext)?;
+
+        // The peer has moved on to its next key; follow before the next message.
+        if is_rekey(&plaintext) {
+            transport.rekey_incoming();
+            self.rekeys_received.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Tells the peer why we are hanging up, then closes the connection. The
+    /// reason is skipped rather than waited for if another send is stuck, and
+    /// given up on after `DISCONNECT_TIMEOUT` if the peer isn't reading.
+    pub fn disconnect(&self, reason: DisconnectReason, message: &str) {
+        // The timeout is on the socket, so it covers the writer's handle too.
+        self.closer.set_write_timeout(Some(DISCONNECT_TIMEOUT)).ok();
+        if let Ok(mut writer) = self.writer.try_lock() {
+            let _ = self.send_locked(&mut writer, &[frame_disconnect(reason, message)]);
+        }
+        self.close();
+    }
+
+    /// Closes the connection, waking up anything blocked on it.
+    pub fn close(&self) {
+        self.closer.shutdown(Shutdown::Both).ok();
+    }
+
+    /// The tunnel MTU our end of the path can carry right now.
+    pub fn tunnel_mtu(&self) -> io::Result<i32> {
+        tcp_tunnel_mtu(&self.closer)
+    }
+
+    /// How many times we have rotated our sending key.
+    pub fn rekeys_sent(&self) -> u64 {
+        self.rekeys_sent.load(Ordering::Relaxed)
+    }
+
+    /// How many times the peer has rotated its sending key.
+    pub fn rekeys_received(&self) -> u64 {
+        self.rekeys_received.load(Ordering::Relaxed)
+    }
+
+    /// Features both sides agreed on (see `framing::FEATURE_*`).
+    pub fn features(&self) -> u32 {
+        self.features.load(Ordering::Acquire)
+    }
+
+    pub fn set_features(&self, features: u32) {
+        self.features.store(features, Ordering::Release);
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features() & feature != 0
+    }
+
+    /// The tunnel MTU the peer advertised for its end of the path, if any.
+    pub fn peer_mtu(&self) -> Option<i32> {
+        Some(self.peer_mtu.load(Ordering::Relaxed)).filter(|&mtu| mtu > 0)
+    }
+
+    pub fn set_peer_mtu(&self, mtu: i32) {
+        self.peer_mtu.store(mtu, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::util::recv_ciphertext;
+    use std::io::{ErrorKind, Write};
+    use std::net::TcpListener;
+
+    const PARAMS: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+    /// Two ends of a fresh NN tunnel over loopback.
+    fn connected() -> (Session, Session) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let mut initiator = snow::Builder::new(PARAMS.parse().unwrap())
+            .build_initiator()
+            .unwrap();
+        let mut responder = snow::Builder::new(PARAMS.parse().unwrap())
+            .build_responder()
+            .unwrap();
+        let (mut msg, mut buf) = ([0u8; 1024], [0u8; 1024]);
+        let len = initiator.write_message(&[], &mut msg).unwrap();
+        responder.read_message(&msg[..len], &mut buf).unwrap();
+        let len = responder.write_message(&[], &mut msg).unwrap();
+        initiator.read_message(&msg[..len], &mut buf).unwrap();
+        (
+            Session::new(initiator.into_transport_mode().unwrap(), client).unwrap(),
+            Session::new(responder.into_transport_mode().unwrap(), server).unwrap(),
+        )
+    }
+
+    fn receive(session: &Session) -> Vec<u8> {
+        let mut stream = session.closer.try_clone().unwrap();
+        session
+            .decrypt(&recv_ciphertext(&mut stream).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn data_after_a_rekey_still_decrypts() {
+        let (client, server) = connected();
+        client.set_features(FEATURE_REKEY);
+
+        // Due by time: the batch goes out under the old key, followed by the Rekey.
+        client.writer.lock().unwrap().last_rekey -= REKEY_AFTER_TIME;
+        client.send(b"before").unwrap();
+        assert_eq!(receive(&server), b"before");
+        assert!(is_rekey(&receive(&server)));
+        client.send(b"after time").unwrap();
+        assert_eq!(receive(&server), b"after time");
+        assert_eq!((client.rekeys_sent(), server.rekeys_received()), (1, 1));
+
+        // Due by bytes.
+        client.writer.lock().unwrap().bytes_since_rekey = REKEY_AFTER_BYTES - 1;
+        client.send(b"over").unwrap();
+        assert_eq!(receive(&server), b"over");
+        assert!(is_rekey(&receive(&server)));
+        client.send(b"after bytes").unwrap();
+        assert_eq!(receive(&server), b"after bytes");
+        assert_eq!((client.rekeys_sent(), server.rekeys_received()), (2, 2));
+
+        // The other direction never rotated and still works.
+        server.send(b"reply").unwrap();
+        assert_eq!(receive(&client), b"reply");
+        assert_eq!((server.rekeys_sent(), client.rekeys_received()), (0, 0));
+    }
+
+    #[test]
+    fn disconnect_gives_up_on_a_peer_that_isnt_reading() {
+        let (_client, session) = connected();
+
+        // Fill both socket buffers, so the next write can only wait.
+        let mut stuffer = session.closer.try_clone().unwrap();
+        stuffer.set_nonblocking(true).unwrap();
+        loop {
+            match stuffer.write(&[0u8; 65536]) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        stuffer.set_nonblocking(false).unwrap();
+
+        let start = Instant::now();
+        session.disconnect(DisconnectReason::Shutdown, "Bye");
+        assert!(start.elapsed() < DISCONNECT_TIMEOUT * 5);
+    }
+}
@@ -1,10 +1,18 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 use snow::TransportState;
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 
-pub const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, and the most plaintext that fits in one after the AEAD tag.
+pub const MAX_MESSAGE_LEN: usize = 65535;
+pub const MAX_PLAINTEXT: usize = MAX_MESSAGE_LEN - 16;
+
+/// Maximum number of frames moved through the data path in one go.
+pub const MAX_BATCH: usize = 64;
+
+// Stay well below IOV_MAX (1024) so a batch is always a single writev.
+const MAX_IOVECS: usize = 2 * MAX_BATCH;
 
 #[macro_export]
 macro_rules! ok_or_continue {
@@ -27,10 +35,7 @@ pub fn read_msg(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
 }
 
 pub fn write_msg(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
-    // NOTE: BlackWire uses framing with a 2 byte size leading data.
-    stream.write_u16::<BigEndian>(msg.len() as u16)?;
-    stream.write_all(msg)?;
-    Ok(())
+    send_ciphertext(stream, msg)
 }
 
 pub fn encrypt(transport: &mut TransportState, plaintext: &[u8]) -> io::Result<Vec<u8>> {
@@ -39,7 +44,8 @@ pub fn encrypt(transport: &mut TransportState, plaintext: &[u8]) -> io::Result<V
         .write_message(plaintext, &mut out)
         .map_err(io::Error::other)?;
 
-    Ok(out[..n].to_vec())
+    out.truncate(n);
+    Ok(out)
 }
 
 pub fn decrypt(transport: &mut TransportState, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
@@ -48,7 +54,8 @@ pub fn decrypt(transport: &mut TransportState, ciphertext: &[u8]) -> io::Result<
         .read_message(ciphertext, &mut out)
         .map_err(io::Error::other)?;
 
-    Ok(out[..n].to_vec())
+    out.truncate(n);
+    Ok(out)
 }
 
 pub fn safe_encrypt(
@@ -67,7 +74,9 @@ pub fn safe_decrypt(
     decrypt(&mut guard, ciphertext)
 }
 
-pub fn recv_ciphertext(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+/// Reads one length-prefixed message. Wrap the stream in a `BufReader` so a
+/// single read syscall can pull in many messages.
+pub fn recv_ciphertext<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
     let len = stream.read_u16::<BigEndian>()? as usize;
 
     let mut data = vec![0u8; len];
@@ -76,8 +85,47 @@ pub fn recv_ciphertext(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
 }
 
 pub fn send_ciphertext(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
-    // NOTE: BlackWire uses framing with a 2 byte size leading data.
-    stream.write_u16::<BigEndian>(msg.len() as u16)?;
-    stream.write_all(msg)?;
+    send_ciphertext_batch(stream, &[msg])
+}
+
+/// Writes every message with its length prefix using as few writev calls as possible.
+pub fn send_ciphertext_batch<T: AsRef<[u8]>>(stream: &mut TcpStream, msgs: &[T]) -> io::Result<()> {
+    // A length that doesn't fit the prefix would desynchronise the stream, so refuse
+    // the whole batch before anything is written.
+    if msgs.iter().any(|m| m.as_ref().len() > MAX_MESSAGE_LEN) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message exceeds the 16-bit length prefix",
+        ));
+    }
+
+    for chunk in msgs.chunks(MAX_IOVECS / 2) {
+        // NOTE: BlackWire uses framing with a 2 byte size leading data.
+        let prefixes: Vec<[u8; 2]> = chunk
+            .iter()
+            .map(|m| (m.as_ref().len() as u16).to_be_bytes())
+            .collect();
+
+        let mut slices: Vec<IoSlice> = Vec::with_capacity(chunk.len() * 2);
+        for (prefix, msg) in prefixes.iter().zip(chunk) {
+            slices.push(IoSlice::new(prefix));
+            slices.push(IoSlice::new(msg.as_ref()));
+        }
+
+        write_all_vectored(stream, &mut slices)?;
+    }
+    Ok(())
+}
+
+fn write_all_vectored(stream: &mut TcpStream, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    // Stable stand-in for `Write::write_all_vectored`.
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
     Ok(())
 }
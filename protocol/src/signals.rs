@@ -0,0 +1,51 @@
+/* SIGINT/SIGTERM ask a binary to stop, SIGHUP to reload its config and keys. Both binaries block
+ * these in `main` before spawning anything, so every thread inherits the mask and the signals are
+ * only ever picked up by whoever calls `Signals::wait`.
+ */
+
+use std::io;
+use std::mem;
+use std::ptr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Stop,
+    Reload,
+}
+
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    /// Blocks the signals we handle. Threads spawned before this won't have them blocked.
+    pub fn block() -> io::Result<Self> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut set);
+            for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                libc::sigaddset(&mut set, sig);
+            }
+        }
+
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        Ok(Self { set })
+    }
+
+    /// Sleeps until one of the signals arrives.
+    pub fn wait(&self) -> io::Result<Signal> {
+        let mut sig: libc::c_int = 0;
+        let err = unsafe { libc::sigwait(&self.set, &mut sig) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+
+        Ok(match sig {
+            libc::SIGHUP => Signal::Reload,
+            _ => Signal::Stop,
+        })
+    }
+}
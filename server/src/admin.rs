@@ -0,0 +1,117 @@
+/* The admin socket is a Unix socket only root can open. Each connection is sent a snapshot of
+ * the connected peers, followed by any peers that are about to expire and any key rotation still
+ * in progress, as text and then closed;
+ * `server status` just prints it.
+ */
+
+use crate::client::table::SharedClientTable;
+use crate::config::ServerConfig;
+use protocol::auth::SharedAuth;
+use protocol::auth::peer::{Peer, format_duration};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub fn open(path: &Path) -> io::Result<UnixListener> {
+    // A socket left behind by a previous run would make the bind fail.
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+pub fn serve(listener: UnixListener, table: SharedClientTable, auth: SharedAuth) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if let Err(e) = write_status(&mut stream, &table, &auth) {
+                    eprintln!("Admin socket error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Admin accept error: {}", e),
+        }
+    }
+}
+
+fn write_status(
+    out: &mut impl Write,
+    table: &SharedClientTable,
+    auth: &SharedAuth,
+) -> io::Result<()> {
+    let mut clients = table.all_senders();
+    clients.sort_by(|a, b| a.peer.name.cmp(&b.peer.name));
+
+    writeln!(
+        out,
+        "{:<16} {:<17} {:<24} {:>8} {:>8} {:>6} {:>8}  TAGS",
+        "PEER", "MAC", "ADDRESS", "UPTIME", "IDLE", "REKEYS", "EXPIRES"
+    )?;
+    for ci in clients {
+        let mac = ci
+            .mac
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        let expires = ci
+            .peer
+            .expires_in()
+            .map(format_duration)
+            .unwrap_or_else(|| "-".to_string());
+        writeln!(
+            out,
+            "{:<16} {:<17} {:<24} {:>7}s {:>7}s {:>6} {:>8}  {}",
+            ci.peer.name,
+            mac,
+            ci.addr.to_string(),
+            ci.established.elapsed().as_secs(),
+            ci.idle().as_secs(),
+            ci.session.rekeys_sent() + ci.session.rekeys_received(),
+            expires,
+            ci.peer.tags.join(","),
+        )?;
+    }
+
+    // Whether or not they are connected.
+    let auth = auth.lock().unwrap();
+    let mut warnings: Vec<String> = auth
+        .peers
+        .values()
+        .filter_map(Peer::expiry_warning)
+        .collect();
+    if let Some(previous) = &auth.previous
+        && let Some(left) = previous.expires_in()
+    {
+        warnings.push(format!(
+            "Previous server key still accepted for {}",
+            format_duration(left)
+        ));
+    }
+    drop(auth);
+    if !warnings.is_empty() {
+        warnings.sort();
+        writeln!(out)?;
+        for warning in warnings {
+            writeln!(out, "{}", warning)?;
+        }
+    }
+    Ok(())
+}
+
+/// `server status`
+pub fn command(config: &ServerConfig) -> io::Result<()> {
+    let path = config
+        .admin_socket
+        .as_deref()
+        .ok_or_else(|| io::Error::other("The admin socket is turned off"))?;
+
+    let mut stream = UnixStream::connect(path)?;
+    io::copy(&mut stream, &mut io::stdout())?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+/* An offline CA for signed credentials (see `protocol::auth::credential`). Its private key never
+ * needs to be on the server; only the `.pub` file goes into `trusted_cas` there.
+ */
+
+use ed25519_dalek::SigningKey;
+use protocol::auth::credential::Credential;
+use rand::RngCore;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+const USAGE: &str = "Usage: server ca new <path> | \
+                     server ca sign <ca key> <name> <client public key> <days> [attributes file]";
+
+/// `server ca new ...` and `server ca sign ...`
+pub fn command(args: &[String]) -> io::Result<()> {
+    match args.first().map(String::as_str) {
+        Some("new") if args.len() == 2 => new_ca(Path::new(&args[1])),
+        Some("sign") if (5..=6).contains(&args.len()) => sign(&args[1..]),
+        _ => Err(usage()),
+    }
+}
+
+/// Writes `<path>.key` and `<path>.pub`.
+fn new_ca(path: &Path) -> io::Result<()> {
+    let mut seed = Zeroizing::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(seed.as_mut());
+    let key = SigningKey::from_bytes(&seed);
+
+    let key_path = path.with_extension("key");
+    let pub_path = path.with_extension("pub");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&key_path)?;
+    writeln!(
+        file,
+        "{}",
+        Zeroizing::new(hex::encode(key.to_bytes())).as_str()
+    )?;
+    fs::write(
+        &pub_path,
+        format!("{}\n", hex::encode(key.verifying_key().as_bytes())),
+    )?;
+
+    println!("Wrote {} and {}", key_path.display(), pub_path.display());
+    println!(
+        "Add the contents of {} to `trusted_cas` on the server",
+        pub_path.display()
+    );
+    Ok(())
+}
+
+/// Prints a credential for the client to store as its `credential` file.
+fn sign(args: &[String]) -> io::Result<()> {
+    let seed = read_hex(&args[0])?;
+    let ca =
+        SigningKey::try_from(seed.as_slice()).map_err(|_| invalid("CA key must be 32 bytes"))?;
+
+    let public_key = hex::decode(args[2].trim()).map_err(|_| invalid("Invalid client key"))?;
+    let days: u64 = args[3]
+        .parse()
+        .map_err(|_| invalid("Invalid number of days"))?;
+    let attributes = match args.get(4) {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let not_after = days
+        .checked_mul(24 * 60 * 60)
+        .and_then(|secs| now.checked_add(secs))
+        .ok_or_else(|| invalid("Too many days"))?;
+    let credential = Credential {
+        public_key,
+        name: args[1].clone(),
+        not_before: now,
+        not_after,
+        attributes,
+    };
+
+    println!("{}", hex::encode(credential.sign(&ca)?));
+    Ok(())
+}
+
+fn read_hex(path: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    let text = Zeroizing::new(fs::read_to_string(path)?);
+    hex::decode(text.trim())
+        .map(Zeroizing::new)
+        .map_err(|_| invalid("Invalid hex in CA key"))
+}
+
+fn usage() -> io::Error {
+    invalid(USAGE)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
@@ -1,4 +1,6 @@
 pub mod acceptor;
 pub mod handler;
+pub mod limits;
+pub mod reaper;
 pub mod table;
 pub mod types;
@@ -1,33 +1,77 @@
 use crate::ByteSender;
 use crate::client::handler::client_thread;
+use crate::client::limits::RateLimiter;
 use crate::client::table::SharedClientTable;
+use crate::config::{ServerConfig, SharedConfig};
 use protocol::auth::SharedAuth;
+use protocol::noise::guard::HandshakeGuard;
 
+use std::io;
 use std::net::TcpListener;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
+/// Runs until `stopping` is set and the listener is shut down to wake it up.
 pub fn accept_new_clients(
+    listener: TcpListener,
     table: SharedClientTable,
     tap_tx: ByteSender,
     auth: SharedAuth,
-    port: u32,
+    shared_config: SharedConfig,
+    stopping: Arc<AtomicBool>,
 ) {
     // Accept new clients, these are clients joining the LAN
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    println!("Listening on port {}", port);
+    let config = shared_config.read().unwrap().clone();
+    let guard = Arc::new(HandshakeGuard::new(
+        config.cookie_threshold,
+        config.allow_legacy_handshake,
+    ));
+    let mut limiter = RateLimiter::new(config.handshakes_per_minute);
 
     for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+
         match stream {
             Ok(sock) => {
-                println!("New client {:?}", sock.peer_addr());
+                let Ok(addr) = sock.peer_addr() else {
+                    continue;
+                };
+                println!("New client {}", addr);
+
+                // Each connection gets whatever config is current when it arrives.
+                let config = shared_config.read().unwrap().clone();
+                guard.configure(config.cookie_threshold, config.allow_legacy_handshake);
+                limiter.set_per_minute(config.handshakes_per_minute);
+
+                // Turn connections away before they cost us a thread.
+                if guard.pending() >= config.max_pending_handshakes {
+                    eprintln!("Too many pending handshakes, dropping {}", addr);
+                    continue;
+                }
+                if !limiter.allow(addr.ip()) {
+                    eprintln!("{} is connecting too often, dropping it", addr.ip());
+                    continue;
+                }
+                let pending = guard.begin();
 
                 let table_for_client = Arc::clone(&table);
                 let tap_tx_for_client = tap_tx.clone();
                 let auth_for_client = Arc::clone(&auth);
+                let config_for_client = Arc::clone(&config);
+                let guard_for_client = Arc::clone(&guard);
                 thread::spawn(move || {
-                    match client_thread(sock, table_for_client, tap_tx_for_client, auth_for_client)
-                    {
+                    match client_thread(
+                        sock,
+                        table_for_client,
+                        tap_tx_for_client,
+                        auth_for_client,
+                        config_for_client,
+                        guard_for_client,
+                        pending,
+                    ) {
                         Ok(_) => {}
                         Err(e) => {
                             println!("A client has errored: {}", e);
@@ -40,6 +84,15 @@ pub fn accept_new_clients(
             }
         }
     }
+}
+
+pub fn open_listener(config: &ServerConfig) -> io::Result<TcpListener> {
+    // A socket stays in the namespace it was created in, so only the bind needs to happen there.
+    #[cfg(target_os = "linux")]
+    return tap::netns::in_netns(config.uplink_netns.as_deref(), || {
+        TcpListener::bind(format!("0.0.0.0:{}", config.port))
+    });
 
-    loop {}
+    #[allow(unreachable_code)]
+    TcpListener::bind(format!("0.0.0.0:{}", config.port))
 }
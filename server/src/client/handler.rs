@@ -1,17 +1,29 @@
+use crate::client::limits::Deadline;
 use crate::client::table::SharedClientTable;
 use crate::client::types::ClientInfo;
+use crate::config::{BASE_DIR, ServerConfig};
+use crate::enrol::redeem_token;
+use crate::net::mac::derived_mac;
 use crate::{ByteReceiver, ByteSender};
 use protocol::auth::SharedAuth;
+use protocol::compress::{Compression, decompress_message};
+use protocol::fragment::Reassembler;
 use protocol::framing::{
-    ControlType, OpCode, classify_frame, frame_control, frame_ethernet, parse_control_frame,
+    ControlType, DisconnectReason, FEATURE_MTU, OpCode, SUPPORTED_FEATURES, classify_frame,
+    frame_control, frame_handshake, frame_mtu, frame_server_key, parse_control_frame,
+    parse_disconnect, parse_ethernet_batch, parse_handshake,
 };
-use protocol::noise::server::server_handshake;
-use protocol::noise::util::{recv_ciphertext, safe_decrypt, safe_encrypt, send_ciphertext};
+use protocol::mtu::tcp_tunnel_mtu;
+use protocol::noise::enrol::server_enrol;
+use protocol::noise::guard::{HandshakeGuard, Pending};
+use protocol::noise::server::{Handshake, read_hello, server_handshake};
+use protocol::noise::session::Session;
+use protocol::noise::util::{MAX_BATCH, recv_ciphertext};
 use protocol::ok_or_continue;
-use snow::{Keypair, TransportState};
-use std::io;
+use std::io::{self, BufReader};
 use std::net::{Shutdown, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 
 pub fn client_thread(
@@ -19,103 +31,181 @@ pub fn client_thread(
     table: SharedClientTable,
     tap_tx: ByteSender,
     auth: SharedAuth,
+    config: Arc<ServerConfig>,
+    guard: Arc<HandshakeGuard>,
+    pending: Pending,
 ) -> io::Result<()> {
-    let (tx_to_client, rx_from_tap) = crossbeam_channel::unbounded::<Vec<u8>>();
-
-    // Get MAC for client and store this client.
     let addr = sock.peer_addr()?;
-    let ci: Arc<ClientInfo> = table.add_new_client(addr, tx_to_client);
 
-    println!("Assigned MAC {:02x?}", ci.mac);
+    // Give up on clients that don't finish the handshake in time.
+    let deadline = Deadline::start(sock.try_clone()?, config.handshake_timeout);
 
     // Perform Noise handshake.
-    let server_keypair = {
-        let a = auth.lock().unwrap();
-        Keypair {
-            public: a.keypair.public.clone(),
-            private: a.keypair.private.clone(),
-        }
-    };
+    let server_keys = auth.lock().unwrap().static_keys();
 
-    let (transport, client_static) = server_handshake(&mut sock, &server_keypair)?;
-    let wrapped_transport = Arc::new(Mutex::new(transport));
+    let hello = read_hello(&mut sock, &server_keys, &config.ciphers, &guard)?;
 
-    // Check the client static key is allowed.
-    {
-        auth.lock().unwrap().reload_if_modified()?;
+    if hello.is_enrolment() {
+        let name = server_enrol(&mut sock, hello, &server_keys[0], |key, token| {
+            redeem_token(Path::new(BASE_DIR), token, key)
+        })?;
+        println!("Enrolled new client `{}` from {}", name, addr);
+        return Ok(());
     }
-    {
-        let locked_auth = auth.lock().unwrap();
-        if !locked_auth.is_allowed(&client_static) {
+
+    // Find out who the client is, and check this isn't a replayed handshake, before answering.
+    let handshake = server_handshake(
+        &mut sock,
+        hello,
+        &server_keys,
+        |key, timestamp, credential| {
+            let peer = auth.lock().unwrap().authenticate(key, credential)?;
+            guard.check_timestamp(key, timestamp)?;
+            Ok(peer)
+        },
+        |key| auth.lock().unwrap().psk_for(key).map(<[u8]>::to_vec),
+    );
+    let Handshake {
+        transport,
+        client_static,
+        peer,
+        server_static,
+    } = match handshake {
+        Ok(handshake) => handshake,
+        Err(e) => {
             sock.shutdown(Shutdown::Both).ok();
-            table.remove(ci.mac);
             drop(sock);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Unauthorized client",
-            ));
+            return Err(e);
         }
+    };
+
+    if let Some(warning) = peer.expiry_warning() {
+        println!("{}", warning);
+    }
+
+    // The same client always gets the same MAC, so the LAN keeps recognising it.
+    let mac = peer.mac.unwrap_or_else(|| derived_mac(&client_static));
+
+    drop(deadline);
+    drop(pending);
+
+    // Only an authenticated client gets a MAC and a place in the table.
+    let session = Arc::new(Session::new(transport, sock.try_clone()?)?);
+    let (tx_to_client, rx_from_tap) = crossbeam_channel::unbounded::<Vec<u8>>();
+    let (ci, replaced) = table.add_new_client(
+        peer,
+        addr,
+        tx_to_client,
+        Arc::clone(&session),
+        mac,
+        config.duplicate_sessions,
+    )?;
+    for old in replaced {
+        println!("Replacing session with {}", old);
+        old.session
+            .disconnect(DisconnectReason::Replaced, "Replaced by a new session");
     }
 
+    println!("Assigned MAC {:02x?} to {}", ci.mac, ci);
+
     // Perform BlackWire handshake.
-    client_negotiation(&ci, &wrapped_transport, &mut sock)?;
+    // Clients that still know us by our previous key learn the new one.
+    let new_key = (server_static != 0).then(|| server_keys[0].public.as_slice());
+    if let Err(e) = client_negotiation(&ci, &session, new_key) {
+        table.remove(&ci);
+        return Err(e);
+    }
 
     // Client is now ready to start transmitting data!
     // The event loop just deals with encrypting and forwarding to the client.
-    let sock_writer = sock.try_clone().unwrap();
-    let transport_writer = Arc::clone(&wrapped_transport);
-    thread::spawn(move || client_write(sock_writer, rx_from_tap, transport_writer));
+    let session_writer = Arc::clone(&session);
+    thread::spawn(move || client_write(rx_from_tap, session_writer));
 
-    client_read(sock, tap_tx, wrapped_transport);
+    client_read(sock, tap_tx, &ci, config.features(&ci.peer), config.mtu);
 
-    table.remove(ci.mac);
+    table.remove(&ci);
+    session.close();
 
     Ok(())
 }
 
 fn client_negotiation(
     ci: &Arc<ClientInfo>,
-    transport: &Arc<Mutex<TransportState>>,
-    mut sock: &mut TcpStream,
+    session: &Session,
+    new_key: Option<&[u8]>,
 ) -> io::Result<()> {
     // Send MAC address to the client.
     let mac_frame = frame_control(ControlType::AssignMac, &ci.mac);
+    session.send(&mac_frame)?;
 
-    let ciphertext = safe_encrypt(&transport, &mac_frame)?;
-    send_ciphertext(&mut sock, &ciphertext)?;
+    if let Some(key) = new_key {
+        println!("Telling {} about our new key", ci);
+        session.send(&frame_server_key(key))?;
+    }
     Ok(())
 }
 
-fn client_write(
-    mut sock: TcpStream,
-    data_stream: ByteReceiver,
-    transport: Arc<Mutex<TransportState>>,
-) {
-    for frame in data_stream.iter() {
-        let framed = frame_ethernet(&frame);
-        let ciphertext = ok_or_continue!(safe_encrypt(&transport, &framed));
-        let _ = send_ciphertext(&mut sock, &ciphertext);
+fn client_write(data_stream: ByteReceiver, session: Arc<Session>) {
+    while let Ok(first) = data_stream.recv() {
+        // Coalesce everything already queued into as few messages as possible.
+        let mut frames = vec![first];
+        frames.extend(data_stream.try_iter().take(MAX_BATCH - 1));
+
+        let _ = session.send_frames(&frames);
     }
 }
 
-fn client_read(
-    mut sock: TcpStream,
-    tap_channel: ByteSender,
-    transport: Arc<Mutex<TransportState>>,
-) {
+fn client_read(sock: TcpStream, tap_channel: ByteSender, ci: &ClientInfo, features: u32, mtu: i32) {
+    let session = &ci.session;
+    let mut reader = BufReader::new(sock);
+    let mut reassembler = Reassembler::new();
     loop {
-        // Read and decrypt message from TcpStream
-        let ciphertext = ok_or_continue!(recv_ciphertext(&mut sock));
-        let plaintext = ok_or_continue!(safe_decrypt(&transport, &ciphertext));
+        // Read and decrypt message from TcpStream. Either failing means the connection is done.
+        let plaintext = match recv_ciphertext(&mut reader).and_then(|c| session.decrypt(&c)) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                println!("Client {} disconnected: {}", ci, e);
+                break;
+            }
+        };
+        ci.touch();
+
+        let Some(plaintext) = ok_or_continue!(reassembler.push(plaintext)) else {
+            continue;
+        };
+        let compression = Compression::from_features(session.features());
+        let plaintext = ok_or_continue!(decompress_message(plaintext, compression));
 
         // Classify message
         match ok_or_continue!(classify_frame(&plaintext)) {
             OpCode::Control => {
                 let (ctrl_type, payload) = ok_or_continue!(parse_control_frame(&plaintext));
                 match ctrl_type {
-                    ControlType::Handshake => {}
+                    ControlType::Handshake => {
+                        // Answer with what we both support, then start using it.
+                        let features = ok_or_continue!(parse_handshake(payload))
+                            & SUPPORTED_FEATURES
+                            & features;
+                        ok_or_continue!(session.send(&frame_handshake(features)));
+                        session.set_features(features);
+
+                        if features & FEATURE_MTU != 0 {
+                            // Frames also have to fit through `bw0`, whatever the path allows.
+                            let path = ok_or_continue!(tcp_tunnel_mtu(reader.get_ref()));
+                            let mtu = ok_or_continue!(
+                                u16::try_from(path.min(mtu)).map_err(io::Error::other)
+                            );
+                            ok_or_continue!(session.send(&frame_mtu(mtu)));
+                        }
+                    }
                     ControlType::AssignMac => {}
                     ControlType::Pong => {}
+                    ControlType::Mtu => {}
+                    // Only servers have keys to hand out.
+                    ControlType::ServerKey => {}
+                    ControlType::Rekey => {
+                        println!("Client rekeyed ({} so far)", session.rekeys_received());
+                    }
                 }
             }
 
@@ -124,7 +214,18 @@ fn client_read(
                 let ethernet = &plaintext[1..];
                 ok_or_continue!(tap_channel.send(Vec::from(ethernet)));
             }
+            OpCode::EthernetBatch => {
+                for ethernet in ok_or_continue!(parse_ethernet_batch(&plaintext)) {
+                    ok_or_continue!(tap_channel.send(Vec::from(ethernet)));
+                }
+            }
             OpCode::IP => {}
+            OpCode::Disconnect => {
+                let (reason, message) = ok_or_continue!(parse_disconnect(&plaintext));
+                println!("Client {} disconnected ({:?}): {}", ci, reason, message);
+                break;
+            }
+            OpCode::Compressed | OpCode::Fragment => {}
         }
     }
 }
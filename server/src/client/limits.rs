@@ -0,0 +1,128 @@
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use std::collections::HashMap;
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Forget about addresses once this many are tracked and their buckets have refilled.
+const MAX_TRACKED: usize = 4096;
+
+/// Shuts a socket down if the handshake on it hasn't finished in time, which
+/// unblocks whatever read or write the handshake is stuck in. Dropping the
+/// deadline cancels it.
+pub struct Deadline {
+    _cancel: Sender<()>,
+}
+
+impl Deadline {
+    pub fn start(sock: TcpStream, timeout: Duration) -> Self {
+        let (cancel, cancelled) = crossbeam_channel::bounded::<()>(0);
+
+        thread::spawn(move || {
+            if cancelled.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                sock.shutdown(Shutdown::Both).ok();
+            }
+        });
+
+        Self { _cancel: cancel }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Limits how many connections each source address may open, as a token
+/// bucket that refills `per_minute` tokens a minute. Zero means no limit.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn set_per_minute(&mut self, per_minute: u32) {
+        self.per_minute = per_minute;
+    }
+
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let capacity = self.per_minute as f64;
+        let rate = capacity / 60.0;
+
+        if self.buckets.len() >= MAX_TRACKED {
+            self.buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            last: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(capacity);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn zero_means_no_limit() {
+        let mut limiter = RateLimiter::new(0);
+        assert!((0..1000).all(|_| limiter.allow(A)));
+    }
+
+    #[test]
+    fn each_address_gets_its_own_burst() {
+        let mut limiter = RateLimiter::new(3);
+        assert!((0..3).all(|_| limiter.allow(A)));
+        assert!(!limiter.allow(A));
+        assert!(limiter.allow(B));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut limiter = RateLimiter::new(3);
+        assert!((0..3).all(|_| limiter.allow(A)));
+        assert!(!limiter.allow(A));
+
+        // Twenty seconds buys one more connection at three a minute.
+        let bucket = limiter.buckets.get_mut(&A).unwrap();
+        bucket.last -= Duration::from_secs(20);
+        assert!(limiter.allow(A));
+        assert!(!limiter.allow(A));
+    }
+
+    #[test]
+    fn a_new_limit_applies_straight_away() {
+        let mut limiter = RateLimiter::new(0);
+        limiter.set_per_minute(1);
+        assert!(limiter.allow(A));
+        assert!(!limiter.allow(A));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::client::table::{ClientTable, SharedClientTable};
+use crate::config::SharedConfig;
+use protocol::auth::SharedAuth;
+use protocol::framing::DisconnectReason;
+use std::thread;
+use std::time::Duration;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Hangs up on sessions that have been quiet or alive for too long, or whose peer
+/// has expired. Their clients have to handshake again to carry on.
+pub fn reap_sessions(table: SharedClientTable, auth: SharedAuth, config: SharedConfig) {
+    loop {
+        thread::sleep(REAP_INTERVAL);
+
+        drop_revoked(&table, &auth);
+
+        let config = config.read().unwrap().clone();
+
+        for ci in table.all_senders() {
+            // Peer files can override the server wide limits.
+            let max_lifetime = ci.peer.max_session_lifetime_or(config.max_session_lifetime);
+            let idle_timeout = ci.peer.idle_timeout_or(config.idle_timeout);
+
+            let expired = if max_lifetime.is_some_and(|max| ci.established.elapsed() >= max) {
+                Some((
+                    DisconnectReason::LifetimeExceeded,
+                    "Session lifetime exceeded",
+                ))
+            } else if idle_timeout.is_some_and(|max| ci.idle() >= max) {
+                Some((DisconnectReason::IdleTimeout, "Session idle for too long"))
+            } else {
+                None
+            };
+
+            if let Some((reason, message)) = expired {
+                println!("Closing session with {}: {}", ci, message);
+                ci.session.disconnect(reason, message);
+            }
+        }
+    }
+}
+
+/// Hangs up on clients whose peer has been removed, disabled, revoked or has expired
+/// since they connected. Credentials are checked again too, in case their CA was dropped.
+/// Disconnecting can take a while, so it happens after `auth` is unlocked again.
+pub fn drop_revoked(table: &ClientTable, auth: &SharedAuth) {
+    let revoked: Vec<_> = {
+        let auth = auth.lock().unwrap();
+        table
+            .all_senders()
+            .into_iter()
+            .filter_map(|ci| {
+                auth.authenticate(&ci.peer.public_key, ci.peer.credential.as_deref())
+                    .err()
+                    .map(|e| (ci, e))
+            })
+            .collect()
+    };
+
+    for (ci, e) in revoked {
+        println!("Closing session with {}: {}", ci, e);
+        ci.session
+            .disconnect(DisconnectReason::Revoked, &e.to_string());
+    }
+}
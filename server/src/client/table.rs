@@ -1,7 +1,11 @@
 use crate::ByteSender;
 use crate::client::types::ClientInfo;
+use crate::config::DuplicatePolicy;
 use crate::net::mac::{Mac, generate_mac};
+use protocol::auth::peer::Peer;
+use protocol::noise::session::Session;
 use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
@@ -18,14 +22,13 @@ impl ClientTable {
         }
     }
 
-    pub fn insert(&self, mac: Mac, info: Arc<ClientInfo>) {
+    /// Removes the client, unless its MAC has already been handed to a session that
+    /// replaced it.
+    pub fn remove(&self, ci: &Arc<ClientInfo>) {
         let mut lock = self.map.lock().unwrap();
-        lock.insert(mac, info);
-    }
-
-    pub fn remove(&self, mac: Mac) -> Option<Arc<ClientInfo>> {
-        let mut lock = self.map.lock().unwrap();
-        lock.remove(&mac)
+        if lock.get(&ci.mac).is_some_and(|c| Arc::ptr_eq(c, ci)) {
+            lock.remove(&ci.mac);
+        }
     }
 
     pub fn get(&self, mac: Mac) -> Option<Arc<ClientInfo>> {
@@ -33,30 +36,120 @@ impl ClientTable {
         lock.get(&mac).cloned()
     }
 
-    pub fn generate_unique_mac(&self) -> Mac {
-        let lock = self.map.lock().unwrap();
-        generate_mac(&mut lock.keys())
-    }
-
-    pub fn all_macs(&self) -> Vec<Mac> {
-        let lock = self.map.lock().unwrap();
-        lock.keys().cloned().collect()
+    pub fn is_empty(&self) -> bool {
+        self.map.lock().unwrap().is_empty()
     }
 
     pub fn all_senders(&self) -> Vec<Arc<ClientInfo>> {
         self.map.lock().unwrap().values().cloned().collect()
     }
 
-    pub fn add_new_client(&self, addr: SocketAddr, bs: ByteSender) -> Arc<ClientInfo> {
-        let mac = self.generate_unique_mac();
-        let info = ClientInfo {
-            mac: mac,
-            sender: bs,
-            addr: addr,
-        };
+    /// Adds a newly authenticated client, applying `policy` if its peer is already connected.
+    /// Returns the new entry and any sessions it replaced, which the caller should close.
+    pub fn add_new_client(
+        &self,
+        peer: Peer,
+        addr: SocketAddr,
+        bs: ByteSender,
+        session: Arc<Session>,
+        mut preferred_mac: Mac,
+        policy: DuplicatePolicy,
+    ) -> io::Result<(Arc<ClientInfo>, Vec<Arc<ClientInfo>>)> {
+        // Everything happens under one lock, so two clients can't end up with the same MAC
+        // or both get past the policy.
+        let mut lock = self.map.lock().unwrap();
+
+        let existing: Vec<Mac> = lock
+            .values()
+            .filter(|c| c.peer.public_key == peer.public_key)
+            .map(|c| c.mac)
+            .collect();
+
+        let mut replaced = Vec::new();
+        match policy {
+            _ if existing.is_empty() => {}
+            DuplicatePolicy::Replace => {
+                // The new session carries on where the old one left off, MAC and all.
+                preferred_mac = existing[0];
+                replaced = existing.iter().filter_map(|mac| lock.remove(mac)).collect();
+            }
+            DuplicatePolicy::Reject => {
+                return Err(io::Error::other(format!(
+                    "Peer {} is already connected",
+                    peer
+                )));
+            }
+            DuplicatePolicy::Allow(max) if existing.len() >= max => {
+                return Err(io::Error::other(format!(
+                    "Peer {} already has {} sessions",
+                    peer, max
+                )));
+            }
+            DuplicatePolicy::Allow(_) => {}
+        }
+
+        let mac = generate_mac(&mut lock.keys(), preferred_mac);
+        let info = ClientInfo::new(peer, mac, bs, addr, session);
 
         let safe = Arc::new(info);
-        self.insert(mac, Arc::clone(&safe));
-        safe
+        lock.insert(mac, Arc::clone(&safe));
+        Ok((safe, replaced))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect;
+
+    const MAC: Mac = [0x02, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn replacing_hands_the_mac_to_the_new_session() {
+        let table = ClientTable::new();
+        let first = connect(&table, "laptop", 1, MAC, DuplicatePolicy::Replace).unwrap();
+        let second = connect(
+            &table,
+            "laptop",
+            1,
+            [0x02, 0, 0, 0, 0, 2],
+            DuplicatePolicy::Replace,
+        )
+        .unwrap();
+        assert_eq!(second.ci.mac, MAC);
+        assert_eq!(second.replaced.len(), 1);
+        assert!(Arc::ptr_eq(&second.replaced[0], &first.ci));
+        assert!(Arc::ptr_eq(&table.get(MAC).unwrap(), &second.ci));
+    }
+
+    #[test]
+    fn rejecting_keeps_the_old_session() {
+        let table = ClientTable::new();
+        let first = connect(&table, "laptop", 1, MAC, DuplicatePolicy::Reject).unwrap();
+        assert!(connect(&table, "laptop", 1, MAC, DuplicatePolicy::Reject).is_err());
+        // Other peers aren't affected.
+        connect(
+            &table,
+            "phone",
+            2,
+            [0x02, 0, 0, 0, 0, 2],
+            DuplicatePolicy::Reject,
+        )
+        .unwrap();
+        assert!(Arc::ptr_eq(&table.get(MAC).unwrap(), &first.ci));
+    }
+
+    #[test]
+    fn allowing_caps_sessions_and_gives_each_a_mac() {
+        let table = ClientTable::new();
+        let policy = DuplicatePolicy::Allow(2);
+        let first = connect(&table, "laptop", 1, MAC, policy).unwrap();
+        let second = connect(&table, "laptop", 1, MAC, policy).unwrap();
+        assert!(second.replaced.is_empty());
+        assert_ne!(first.ci.mac, second.ci.mac);
+        assert!(connect(&table, "laptop", 1, MAC, policy).is_err());
+
+        table.remove(&first.ci);
+        connect(&table, "laptop", 1, MAC, policy).unwrap();
     }
 }
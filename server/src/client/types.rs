@@ -1,9 +1,59 @@
 use crate::ByteSender;
 use crate::net::mac::Mac;
+use protocol::auth::peer::Peer;
+use protocol::noise::session::Session;
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 pub struct ClientInfo {
+    /// Who the client authenticated as, as of its handshake.
+    pub peer: Peer,
     pub mac: Mac,
     pub sender: ByteSender,
     pub addr: SocketAddr,
+    pub session: Arc<Session>,
+    pub established: Instant,
+    // Milliseconds after `established` that we last heard from the client.
+    last_rx: AtomicU64,
+}
+
+impl ClientInfo {
+    pub fn new(
+        peer: Peer,
+        mac: Mac,
+        sender: ByteSender,
+        addr: SocketAddr,
+        session: Arc<Session>,
+    ) -> Self {
+        Self {
+            peer,
+            mac,
+            sender,
+            addr,
+            session,
+            established: Instant::now(),
+            last_rx: AtomicU64::new(0),
+        }
+    }
+
+    /// Notes that the client just sent us something.
+    pub fn touch(&self) {
+        let since = self.established.elapsed().as_millis() as u64;
+        self.last_rx.store(since, Ordering::Relaxed);
+    }
+
+    /// How long since we last heard from the client.
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_rx.load(Ordering::Relaxed));
+        self.established.elapsed().saturating_sub(last)
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.peer, self.addr)
+    }
 }
@@ -0,0 +1,157 @@
+use protocol::auth::peer::Peer;
+use protocol::conf::Conf;
+use protocol::framing::{FEATURE_BATCH, FEATURE_FRAGMENT, FEATURE_MTU, FEATURE_REKEY};
+use protocol::mtu::{DEFAULT_TUNNEL_MTU, check_mtu};
+use protocol::noise::params::{ALL_SUITES, Suite};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+pub const BASE_DIR: &str = "/etc/blackwire";
+const CONFIG_FILE: &str = "server.conf";
+
+/// What to do when a peer connects while it already has a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Close the old session and hand its MAC to the new one.
+    Replace,
+    /// Refuse the new connection.
+    Reject,
+    /// Allow up to this many sessions at once.
+    Allow(usize),
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        match s {
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            n => match n.parse() {
+                Ok(n) if n > 0 => Ok(DuplicatePolicy::Allow(n)),
+                _ => Err(io::Error::other(format!(
+                    "Unknown duplicate policy `{}`",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// The running config. Reloading swaps in a new one, while anything already running keeps the
+/// copy it started with.
+pub type SharedConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+pub struct ServerConfig {
+    /// The LAN-facing NIC that `bw0` is bridged onto.
+    pub nic: String,
+    pub port: u32,
+    /// Namespace the TAP device and LAN bridge live in.
+    pub netns: Option<String>,
+    /// Namespace the listening socket is opened in.
+    pub uplink_netns: Option<String>,
+    /// MTU of `bw0`. It is shared by every client, so it is never sized automatically.
+    pub mtu: i32,
+    /// Noise cipher suites clients may use.
+    pub ciphers: Vec<Suite>,
+    /// Pending handshakes above which clients have to present a cookie.
+    pub cookie_threshold: usize,
+    /// Accept clients that predate handshake timestamps (and so can be replayed).
+    pub allow_legacy_handshake: bool,
+    /// How long a client gets to complete its handshake.
+    pub handshake_timeout: Duration,
+    /// Connections beyond this many unfinished handshakes are dropped straight away.
+    pub max_pending_handshakes: usize,
+    /// Connections each source address may open per minute, or 0 for no limit.
+    pub handshakes_per_minute: u32,
+    /// Sessions we haven't heard from for this long are closed.
+    pub idle_timeout: Option<Duration>,
+    /// Sessions are closed this long after their handshake, however busy they are.
+    pub max_session_lifetime: Option<Duration>,
+    /// What happens when a peer connects twice.
+    pub duplicate_sessions: DuplicatePolicy,
+    /// Where `server status` finds us, if anywhere.
+    pub admin_socket: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn load(base: impl AsRef<Path>) -> io::Result<Self> {
+        let conf = Conf::load(base.as_ref().join(CONFIG_FILE))?;
+
+        Ok(Self {
+            nic: conf
+                .get_string("nic")
+                .unwrap_or_else(|| "enp0s20f0u2".to_string()),
+            port: conf.get_or("port", 52123)?,
+            netns: conf.get_string("netns"),
+            uplink_netns: conf.get_string("uplink_netns"),
+            mtu: check_mtu(conf.get_or("mtu", DEFAULT_TUNNEL_MTU)?)?,
+            ciphers: match conf.get("ciphers") {
+                Some(list) => Suite::parse_list(list)?,
+                None => ALL_SUITES.to_vec(),
+            },
+            cookie_threshold: conf.get_or("cookie_threshold", 16)?,
+            allow_legacy_handshake: conf.get_or("allow_legacy_handshake", true)?,
+            handshake_timeout: Duration::from_secs(conf.get_or("handshake_timeout", 10)?),
+            max_pending_handshakes: conf.get_or("max_pending_handshakes", 64)?,
+            handshakes_per_minute: conf.get_or("handshakes_per_minute", 30)?,
+            idle_timeout: optional_secs(&conf, "idle_timeout")?,
+            max_session_lifetime: optional_secs(&conf, "max_session_lifetime")?,
+            duplicate_sessions: conf.get_or("duplicate_sessions", DuplicatePolicy::Replace)?,
+            admin_socket: match conf.get("admin_socket") {
+                Some("none") => None,
+                Some(path) => Some(PathBuf::from(path)),
+                None => Some(PathBuf::from("/run/blackwire.sock")),
+            },
+        })
+    }
+
+    /// Whether moving to `other` needs the listener or devices set up again, which only
+    /// happens at startup.
+    pub fn needs_restart(&self, other: &ServerConfig) -> bool {
+        self.nic != other.nic
+            || self.port != other.port
+            || self.netns != other.netns
+            || self.uplink_netns != other.uplink_netns
+            || self.mtu != other.mtu
+            || self.admin_socket != other.admin_socket
+    }
+
+    /// Optional protocol features we are willing to agree to with `peer`.
+    pub fn features(&self, peer: &Peer) -> u32 {
+        FEATURE_BATCH | FEATURE_FRAGMENT | FEATURE_MTU | FEATURE_REKEY | peer.compression.feature()
+    }
+}
+
+// A number of seconds, where 0 (or leaving it out) means never.
+fn optional_secs(conf: &Conf, key: &str) -> io::Result<Option<Duration>> {
+    let secs: u64 = conf.get_or(key, 0)?;
+    Ok(Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_policies_parse() {
+        assert_eq!(
+            "replace".parse::<DuplicatePolicy>().unwrap(),
+            DuplicatePolicy::Replace
+        );
+        assert_eq!(
+            "reject".parse::<DuplicatePolicy>().unwrap(),
+            DuplicatePolicy::Reject
+        );
+        assert_eq!(
+            "3".parse::<DuplicatePolicy>().unwrap(),
+            DuplicatePolicy::Allow(3)
+        );
+        for bad in ["0", "-1", "Replace", "many", ""] {
+            assert!(bad.parse::<DuplicatePolicy>().is_err(), "{}", bad);
+        }
+    }
+}
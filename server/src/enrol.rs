@@ -0,0 +1,203 @@
+/* One-time enrolment tokens live in `enrol/<name>` under the config directory:
+ *
+ *   token = <hex>
+ *   expires = <unix seconds>
+ *
+ * A token is used up once the key presented with it has been stored, or once it is presented
+ * after it has expired. While it is being redeemed it is moved aside to `enrol/<name>.claimed`,
+ * so two clients can't both spend it.
+ */
+
+use protocol::auth::{add_allowed, valid_peer_name};
+use protocol::conf::Conf;
+use rand::RngCore;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+const ENROL_DIR: &str = "enrol/";
+const TOKEN_LEN: usize = 32;
+const CLAIMED: &str = "claimed";
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `server enrol <name> [lifetime in seconds]`
+pub fn command(base: &Path, args: &[String]) -> io::Result<()> {
+    let Some(name) = args.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: server enrol <name> [lifetime in seconds]",
+        ));
+    };
+    let lifetime =
+        match args.get(1) {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid token lifetime")
+            })?),
+            None => DEFAULT_LIFETIME,
+        };
+
+    let token = create_token(base, name, lifetime)?;
+
+    println!("Enrolment token for `{}`: {}", name, token);
+    println!(
+        "It can be used once within {}s: client enrol {}",
+        lifetime.as_secs(),
+        token
+    );
+    Ok(())
+}
+
+pub fn create_token(base: &Path, name: &str, lifetime: Duration) -> io::Result<String> {
+    if !valid_peer_name(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid peer name `{}`", name),
+        ));
+    }
+
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let expires = (SystemTime::now() + lifetime)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let dir = base.join(ENROL_DIR);
+    fs::create_dir_all(&dir)?;
+
+    // Anyone who can read a token can use it.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(dir.join(name))?;
+    writeln!(file, "token = {}", token)?;
+    writeln!(file, "expires = {}", expires)?;
+
+    Ok(token)
+}
+
+/// Spends a token, storing `key` under the name it was issued for.
+pub fn redeem_token(base: &Path, token: &str, key: &[u8]) -> io::Result<String> {
+    let dir = base.join(ENROL_DIR);
+    let unknown = || io::Error::new(io::ErrorKind::PermissionDenied, "Unknown enrolment token");
+
+    if !dir.exists() {
+        return Err(unknown());
+    }
+
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+        // Tokens being redeemed right now, and anything else that isn't ours.
+        if !valid_peer_name(&name) {
+            continue;
+        }
+        let conf = match Conf::load(&path) {
+            Ok(conf) => conf,
+            Err(e) => {
+                eprintln!("Skipping enrolment token {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let Some(stored) = conf.get("token") else {
+            continue;
+        };
+        if !bool::from(stored.as_bytes().ct_eq(token.as_bytes())) {
+            continue;
+        }
+
+        // Whoever manages to move the file aside owns the token.
+        let claimed = path.with_extension(CLAIMED);
+        match fs::rename(&path, &claimed) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(unknown()),
+            Err(e) => return Err(e),
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if conf.get_or("expires", 0u64).unwrap_or(0) < now {
+            fs::remove_file(&claimed)?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Enrolment token has expired",
+            ));
+        }
+
+        // Only a stored key uses the token up, otherwise it can be tried again.
+        if let Err(e) = add_allowed(base, &name, key) {
+            fs::rename(&claimed, &path)?;
+            return Err(e);
+        }
+        fs::remove_file(&claimed)?;
+        return Ok(name);
+    }
+
+    Err(unknown())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("allowed")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn tokens_are_spent_once_the_key_is_stored() {
+        let dir = base();
+        let base = dir.path();
+        let token = create_token(base, "laptop", DEFAULT_LIFETIME).unwrap();
+        fs::write(base.join(ENROL_DIR).join("garbage"), "not a conf file").unwrap();
+
+        assert_eq!(redeem_token(base, &token, &[1; 32]).unwrap(), "laptop");
+        assert_eq!(
+            fs::read_to_string(base.join("allowed/laptop")).unwrap(),
+            hex::encode([1; 32])
+        );
+        assert!(redeem_token(base, &token, &[1; 32]).is_err());
+    }
+
+    #[test]
+    fn tokens_survive_a_failed_store() {
+        let dir = base();
+        let base = dir.path();
+        fs::write(base.join("allowed/laptop"), hex::encode([2; 32])).unwrap();
+        let token = create_token(base, "laptop", DEFAULT_LIFETIME).unwrap();
+
+        let e = redeem_token(base, &token, &[1; 32]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(base.join(ENROL_DIR).join("laptop").exists());
+
+        fs::remove_file(base.join("allowed/laptop")).unwrap();
+        assert_eq!(redeem_token(base, &token, &[1; 32]).unwrap(), "laptop");
+    }
+
+    #[test]
+    fn expired_tokens_are_spent_without_storing_anything() {
+        let dir = base();
+        let base = dir.path();
+        let token = create_token(base, "laptop", Duration::ZERO).unwrap();
+        let path = base.join(ENROL_DIR).join("laptop");
+        fs::write(&path, format!("token = {}\nexpires = 1\n", token)).unwrap();
+
+        assert!(redeem_token(base, &token, &[1; 32]).is_err());
+        assert!(!path.exists());
+        assert!(!base.join("allowed/laptop").exists());
+    }
+}
@@ -1,80 +1,299 @@
+mod admin;
+mod ca;
 mod client;
+mod config;
+mod enrol;
 mod net;
+mod revoke;
+mod rotate;
+#[cfg(test)]
+mod testing;
+mod watcher;
 
-use client::acceptor::accept_new_clients;
+use client::acceptor::{accept_new_clients, open_listener};
+use client::reaper::{drop_revoked, reap_sessions};
 use client::table::{ClientTable, SharedClientTable};
+use config::{BASE_DIR, ServerConfig, SharedConfig};
 use crossbeam_channel::{Receiver, Sender};
 use net::tap::{read_from_tap, write_to_tap};
 use protocol::auth::{Auth, SharedAuth};
+use protocol::framing::DisconnectReason;
+use protocol::signals::{Signal, Signals};
 use std::io;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use tap::Tap;
 
+/// How long shutdown waits for queues to drain and client threads to exit.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub type ByteSender = Sender<Vec<u8>>;
 pub type ByteReceiver = Receiver<Vec<u8>>;
 type TapHandle = Arc<Tap>;
 
 fn main() -> io::Result<()> {
-    let ethernet_nic = "enp0s20f0u2";
-    let port = 52123;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(cmd) = args.first() {
+        return match cmd.as_str() {
+            "ca" => ca::command(&args[1..]),
+            "enrol" => enrol::command(Path::new(BASE_DIR), &args[1..]),
+            "revoke" => revoke::command(Path::new(BASE_DIR), &args[1..]),
+            "rotate-key" => rotate::command(Path::new(BASE_DIR), &args[1..]),
+            "status" => admin::command(&ServerConfig::load(BASE_DIR)?),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown command `{}`", cmd),
+            )),
+        };
+    }
+
+    // Before any threads exist, so that only the loop below sees these.
+    let signals = Signals::block()?;
+
+    let config = Arc::new(ServerConfig::load(BASE_DIR)?);
 
-    let tap: TapHandle = Arc::new(setup(ethernet_nic).unwrap());
+    let tap: TapHandle = Arc::new(setup(&config)?);
+
+    let listener = open_listener(&config)?;
+    println!("Listening on port {}", config.port);
 
     let table: SharedClientTable = Arc::new(ClientTable::new());
 
-    let auth: SharedAuth = Arc::new(Mutex::new(Auth::new("/etc/blackwire")?));
+    let auth: SharedAuth = Arc::new(Mutex::new(Auth::new(BASE_DIR)?));
+
+    watcher::watch_allowed(Arc::clone(&auth), Arc::clone(&table))?;
+
+    if let Some(path) = &config.admin_socket {
+        let admin_listener = admin::open(path)?;
+        let table_for_admin = Arc::clone(&table);
+        let auth_for_admin = Arc::clone(&auth);
+        thread::spawn(move || admin::serve(admin_listener, table_for_admin, auth_for_admin));
+    }
+
+    let shared_config: SharedConfig = Arc::new(RwLock::new(Arc::clone(&config)));
 
     let (tap_tx, tap_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
 
-    start_threads(table, tap_tx, tap_rx, tap, auth, port);
+    let stopping = Arc::new(AtomicBool::new(false));
+    let waker = listener.try_clone()?;
+
+    let threads = start_threads(
+        listener,
+        Arc::clone(&table),
+        tap_tx,
+        tap_rx,
+        tap,
+        Arc::clone(&auth),
+        Arc::clone(&shared_config),
+        Arc::clone(&stopping),
+    );
+
+    // SIGHUP reloads, anything else stops us. If we can't wait for signals any more, we stop
+    // the same way and report why afterwards.
+    let waited = loop {
+        match signals.wait() {
+            Ok(Signal::Reload) => reload(&shared_config, &auth, &table),
+            Ok(_) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    println!("Shutting down");
+    stopping.store(true, Ordering::SeqCst);
+    // Wakes the acceptor up, which then sees `stopping`.
+    unsafe { libc::shutdown(waker.as_raw_fd(), libc::SHUT_RD) };
+
+    // The bridge is undone however shutdown went.
+    let clean = shutdown(&table, threads);
+    teardown(&config)?;
+    waited?;
 
-    loop {}
+    if !clean? {
+        return Err(io::Error::other(
+            "Stopped without waiting for every client and frame",
+        ));
+    }
+    println!("Stopped");
+    Ok(())
+}
+
+/// Re-reads the config and keys. Connections made from now on use them, and clients that are
+/// no longer allowed in are dropped.
+fn reload(config: &SharedConfig, auth: &SharedAuth, table: &SharedClientTable) {
+    println!("Reloading config and keys");
+
+    match ServerConfig::load(BASE_DIR) {
+        Ok(new) => {
+            let mut current = config.write().unwrap();
+            if current.needs_restart(&new) {
+                eprintln!(
+                    "Changes to nic, port, netns, uplink_netns, mtu or admin_socket need a restart"
+                );
+            }
+            *current = Arc::new(new);
+        }
+        Err(e) => eprintln!("Keeping the old config: {}", e),
+    }
+
+    match Auth::load(BASE_DIR) {
+        Ok(new) => {
+            *auth.lock().unwrap() = new;
+            drop_revoked(table, auth);
+        }
+        Err(e) => eprintln!("Keeping the old keys: {}", e),
+    }
 }
 
-fn setup(nic: &str) -> io::Result<Tap> {
+/// The threads shutdown waits for.
+struct Threads {
+    acceptor: JoinHandle<()>,
+    tap_writer: JoinHandle<()>,
+}
+
+/// Says goodbye to every client and flushes the TAP, giving up after `SHUTDOWN_TIMEOUT`.
+/// Returns whether everything finished in time. The reaper and TAP reader are not joined: they
+/// hold nothing that needs flushing, so they are abandoned and end with the process.
+fn shutdown(table: &SharedClientTable, threads: Threads) -> io::Result<bool> {
+    threads
+        .acceptor
+        .join()
+        .map_err(|_| io::Error::other("Acceptor thread panicked"))?;
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+
+    wait_until(deadline, || {
+        table.all_senders().iter().all(|ci| ci.sender.is_empty())
+    });
+    // Once out of time, clients are just hung up on.
+    for ci in table.all_senders() {
+        if Instant::now() < deadline {
+            ci.session
+                .disconnect(DisconnectReason::Shutdown, "Server shutting down");
+        } else {
+            ci.session.close();
+        }
+    }
+
+    // Client threads take themselves out of the table as they finish.
+    let mut clean = true;
+    if !wait_until(deadline, || table.is_empty()) {
+        eprintln!("Gave up waiting for some clients to disconnect");
+        clean = false;
+    }
+
+    // The writer returns once every client has dropped its sender and the queue is empty.
+    if wait_until(deadline, || threads.tap_writer.is_finished()) {
+        threads
+            .tap_writer
+            .join()
+            .map_err(|_| io::Error::other("TAP writer thread panicked"))?;
+    } else {
+        eprintln!("Gave up waiting for frames to be written to the TAP");
+        clean = false;
+    }
+
+    Ok(clean)
+}
+
+fn wait_until(deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
+
+fn setup(config: &ServerConfig) -> io::Result<Tap> {
     println!("Setting up devices");
 
-    let tap = Tap::new("bw0")?;
-    tap.set_mtu(1400)?;
+    let tap = match &config.netns {
+        Some(netns) => Tap::new_in_netns("bw0", netns)?,
+        None => Tap::new("bw0")?,
+    };
+    tap.set_mtu(config.mtu)?;
     tap.up()?;
 
     println!("Created device `bw0`");
 
     #[cfg(target_os = "linux")]
     {
-        net::bridge::linux::add_qdisc("bw0")?;
-        net::bridge::linux::add_qdisc(nic)?;
-        net::bridge::linux::mirror_traffic("bw0", nic)?;
-        net::bridge::linux::mirror_traffic(nic, "bw0")?;
+        // The bridge has to be set up from the namespace the TAP and NIC live in.
+        let nic = config.nic.as_str();
+        tap::netns::in_netns(config.netns.as_deref(), || {
+            net::bridge::linux::add_qdisc("bw0")?;
+            net::bridge::linux::add_qdisc(nic)?;
+            net::bridge::linux::mirror_traffic("bw0", nic)?;
+            net::bridge::linux::mirror_traffic(nic, "bw0")
+        })?;
     }
 
     Ok(tap)
 }
 
+/// Undoes what `setup` did to the bridge. `bw0` itself goes away when we exit.
+fn teardown(config: &ServerConfig) -> io::Result<()> {
+    if let Some(path) = &config.admin_socket {
+        std::fs::remove_file(path).ok();
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let nic = config.nic.as_str();
+        tap::netns::in_netns(config.netns.as_deref(), || {
+            net::bridge::linux::remove_qdisc("bw0")?;
+            net::bridge::linux::remove_qdisc(nic)
+        })?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn start_threads(
+    listener: TcpListener,
     table: SharedClientTable,
     tap_tx: ByteSender,
     tap_rx: ByteReceiver,
     tap: TapHandle,
     auth: SharedAuth,
-    port: u32,
-) {
-    let table_for_accepter = Arc::clone(&table);
+    config: SharedConfig,
+    stopping: Arc<AtomicBool>,
+) -> Threads {
+    let table_for_reaper = Arc::clone(&table);
+    let auth_for_reaper = Arc::clone(&auth);
+    let config_for_reaper = Arc::clone(&config);
     thread::spawn(move || {
-        accept_new_clients(table_for_accepter, tap_tx, auth, port);
+        reap_sessions(table_for_reaper, auth_for_reaper, config_for_reaper);
+    });
+
+    // Changing the MTU needs a restart, so it can't change under the reader.
+    let mtu = config.read().unwrap().mtu;
+    let table_for_accepter = Arc::clone(&table);
+    let acceptor = thread::spawn(move || {
+        accept_new_clients(listener, table_for_accepter, tap_tx, auth, config, stopping);
     });
 
-    let table_for_writer = Arc::clone(&table);
     let tap_rx_for_writer = tap_rx.clone();
     let tap_for_writer = Arc::clone(&tap);
-    thread::spawn(move || {
-        write_to_tap(tap_for_writer, table_for_writer, tap_rx_for_writer);
+    let tap_writer = thread::spawn(move || {
+        write_to_tap(tap_for_writer, tap_rx_for_writer);
     });
 
     let table_for_reader = Arc::clone(&table);
     let tap_for_reader = Arc::clone(&tap);
     thread::spawn(move || {
-        read_from_tap(tap_for_reader, table_for_reader);
+        read_from_tap(tap_for_reader, table_for_reader, mtu);
     });
+
+    Threads {
+        acceptor,
+        tap_writer,
+    }
 }
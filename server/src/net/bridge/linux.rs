@@ -8,6 +8,14 @@ pub fn add_qdisc(name: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Removes the qdisc again, along with any filters attached to it.
+pub fn remove_qdisc(name: &str) -> io::Result<()> {
+    Command::new("tc")
+        .args(["qdisc", "del", "dev", name, "clsact"])
+        .status()?;
+    Ok(())
+}
+
 pub fn mirror_traffic(nic_a: &str, nic_b: &str) -> io::Result<()> {
     Command::new("tc")
         .args([
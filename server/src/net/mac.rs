@@ -1,11 +1,19 @@
+use blake2::{Blake2s256, Digest};
 use rand::RngCore;
 
 pub type Mac = [u8; 6];
 
-pub fn generate_mac<'a, I>(existing: &mut I) -> Mac
+const MAC_LABEL: &[u8] = b"blackwire-mac";
+
+/// Hands out `preferred` unless it is already taken, in which case a random MAC is used.
+pub fn generate_mac<'a, I>(existing: &mut I, preferred: Mac) -> Mac
 where
     I: Clone + Iterator<Item = &'a Mac>,
 {
+    if !existing.clone().any(|m| m == &preferred) {
+        return preferred;
+    }
+
     loop {
         let mac = random_mac();
 
@@ -15,12 +23,28 @@ where
     }
 }
 
+/// The MAC a peer gets every time it connects, unless its peer file pins one.
+pub fn derived_mac(public_key: &[u8]) -> Mac {
+    let hash = Blake2s256::new()
+        .chain_update(MAC_LABEL)
+        .chain_update(public_key)
+        .finalize();
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&hash[..6]);
+    local_unicast(mac)
+}
+
 fn random_mac() -> Mac {
     let mut rng = rand::thread_rng();
     let mut mac = [0u8; 6];
 
     rng.fill_bytes(&mut mac);
 
+    local_unicast(mac)
+}
+
+fn local_unicast(mut mac: Mac) -> Mac {
     mac[0] &= 0b11111110; // Clear multicast bit
     mac[0] |= 0b00000010; // Set locally administered bit
 
@@ -1,28 +1,32 @@
 use super::mac::Mac;
 use crate::client::table::SharedClientTable;
 use crate::{ByteReceiver, TapHandle};
+use protocol::mtu::max_frame_len;
+use protocol::noise::util::MAX_BATCH;
 use protocol::ok_or_continue;
 
-pub fn write_to_tap(tap: TapHandle, table: SharedClientTable, tap_rx: ByteReceiver) {
-    for frame in tap_rx.iter() {
-        {
-            println!("Writing a frame to TAP!");
-            /*match tap.write(&frame) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error writing to tap: {}", e);
-                }
-            };*/
+pub fn write_to_tap(tap: TapHandle, tap_rx: ByteReceiver) {
+    while let Ok(first) = tap_rx.recv() {
+        // Pick up anything else already queued so it goes out in the same batch.
+        let mut batch = vec![first];
+        batch.extend(tap_rx.try_iter().take(MAX_BATCH - 1));
+
+        let frames: Vec<&[u8]> = batch.iter().map(|f| f.as_slice()).collect();
+        if let Err(e) = tap.write_batch(&frames) {
+            println!("Error writing to tap: {}", e);
         }
     }
 
     // Only reachable if tap_rx is finished.
 }
 
-pub fn read_from_tap(tap: TapHandle, table: SharedClientTable) {
+pub fn read_from_tap(tap: TapHandle, table: SharedClientTable, mtu: i32) {
+    let mut bufs = vec![vec![0u8; max_frame_len(mtu)]; MAX_BATCH];
+    let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|b| &mut b[..]).collect();
+    let mut lens = [0usize; MAX_BATCH];
+
     loop {
-        let mut buf = [0u8; 2000];
-        let n = match tap.read(&mut buf) {
+        let count = match tap.read_batch(&mut slices, &mut lens) {
             Ok(n) => n,
             Err(e) => {
                 println!("Error reading from tap: {}", e);
@@ -30,38 +34,114 @@ pub fn read_from_tap(tap: TapHandle, table: SharedClientTable) {
             }
         };
 
-        if n < 14 {
-            println!("Invalid ethernet frame");
-            continue;
+        let tap_mac: Mac = ok_or_continue!(tap.get_mac());
+
+        for (buf, &n) in slices.iter().zip(&lens).take(count) {
+            forward_frame(&buf[..n], tap_mac, &table);
         }
+    }
+}
 
-        let frame = buf[..n].to_vec();
+fn forward_frame(frame: &[u8], tap_mac: Mac, table: &SharedClientTable) {
+    if frame.len() < 14 {
+        println!("Invalid ethernet frame");
+        return;
+    }
 
-        let dst_mac: Mac = buf[0..6].try_into().unwrap();
-        let src_mac: Mac = buf[6..12].try_into().unwrap();
-        let tap_mac: Mac = ok_or_continue!(tap.get_mac());
+    let dst_mac: Mac = frame[0..6].try_into().unwrap();
+    let src_mac: Mac = frame[6..12].try_into().unwrap();
+
+    if src_mac == tap_mac {
+        // This is OS generated data (we should ignore it!)
+        println!("OS generated traffic ignored.");
+        return;
+    }
+
+    let broadcast: Mac = [0xff; 6];
 
-        if src_mac == tap_mac {
-            // This is OS generated data (we should ignore it!)
-            println!("OS generated traffic ignored.");
-            continue;
+    if dst_mac == broadcast {
+        // Broadcast traffic
+        println!("Broadcast traffic received from LAN.");
+        for client in table.all_senders() {
+            let _ = client.sender.send(frame.to_vec());
         }
+    } else if let Some(client_info) = table.get(dst_mac) {
+        // Unicast traffic
+        println!("Got traffic for client from LAN.");
+        let _ = client_info.sender.send(frame.to_vec());
+    } else {
+        // Unknown unicast
+    }
+}
 
-        let broadcast: Mac = [0xff; 6];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::table::ClientTable;
+    use crate::config::DuplicatePolicy;
+    use crate::testing::connect;
+    use protocol::mtu::DEFAULT_TUNNEL_MTU;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tap::{MemoryTap, Tap, TapImpl};
 
-        if dst_mac == broadcast {
-            // Broadcast traffic
-            println!("Broadcast traffic received from LAN.");
-            for client in table.all_senders() {
-                let _ = client.sender.send(frame.clone());
-            }
-            continue;
-        } else if let Some(client_info) = table.get(dst_mac) {
-            // Unicast traffic
-            println!("Got traffic for client from LAN.");
-            let _ = client_info.sender.send(buf.to_vec());
-        } else {
-            // Unknown unicast
+    const CLIENT_MAC: Mac = [0x02, 0, 0, 0, 0, 0x10];
+    const OTHER_MAC: Mac = [0x02, 0, 0, 0, 0, 0x20];
+
+    fn frame(dst: Mac, src: Mac, payload: &[u8]) -> Vec<u8> {
+        let mut frame = [dst, src].concat();
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn frames_cross_between_lan_and_clients() {
+        let (lan, bw0) = MemoryTap::pair("lan", "bw0");
+        let lan_mac = lan.get_mac().unwrap();
+        let tap = Arc::new(Tap::from_impl(bw0));
+        let table = Arc::new(ClientTable::new());
+        let client = connect(&table, "laptop", 1, CLIENT_MAC, DuplicatePolicy::Replace).unwrap();
+        assert_eq!(client.ci.mac, CLIENT_MAC);
+        assert!(client.replaced.is_empty());
+        assert_eq!(client.ci.addr, client.stream.local_addr().unwrap());
+
+        let reader_tap = Arc::clone(&tap);
+        let reader_table = Arc::clone(&table);
+        thread::spawn(move || read_from_tap(reader_tap, reader_table, DEFAULT_TUNNEL_MTU));
+
+        // Unicast for another MAC goes nowhere, broadcasts and unicast for the client reach it.
+        let timeout = Duration::from_secs(5);
+        lan.write(&frame(OTHER_MAC, lan_mac, b"lost")).unwrap();
+        let broadcast = frame([0xff; 6], lan_mac, b"everyone");
+        lan.write(&broadcast).unwrap();
+        assert_eq!(client.rx.recv_timeout(timeout).unwrap(), broadcast);
+        let unicast = frame(CLIENT_MAC, lan_mac, b"just you");
+        lan.write(&unicast).unwrap();
+        assert_eq!(client.rx.recv_timeout(timeout).unwrap(), unicast);
+        assert!(client.rx.try_recv().is_err());
+
+        // What clients send comes out on the LAN side in order.
+        let (tap_tx, tap_rx) = crossbeam_channel::unbounded();
+        let sent = [
+            frame(lan_mac, CLIENT_MAC, b"one"),
+            frame(lan_mac, CLIENT_MAC, b"two"),
+        ];
+        for frame in &sent {
+            tap_tx.send(frame.clone()).unwrap();
         }
+        drop(tap_tx);
+        write_to_tap(Arc::clone(&tap), tap_rx);
+
+        let mut buf = [0u8; 2048];
+        for frame in &sent {
+            let n = lan.read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], &frame[..]);
+        }
+
+        // `read_from_tap` never returns, so keep its other end open rather than have it spin
+        // on errors for the rest of the run.
+        std::mem::forget(lan);
     }
 }
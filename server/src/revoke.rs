@@ -0,0 +1,27 @@
+use protocol::auth::{Auth, add_revoked};
+use std::io;
+use std::path::Path;
+
+/// `server revoke <name>`: refuses the peer's key from now on, even if its file comes back.
+pub fn command(base: &Path, args: &[String]) -> io::Result<()> {
+    let Some(name) = args.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: server revoke <name>",
+        ));
+    };
+
+    let auth = Auth::load(base)?;
+    let peer = auth
+        .peers
+        .get(name)
+        .ok_or_else(|| io::Error::other(format!("No peer called `{}`", name)))?;
+
+    add_revoked(base, &peer.public_key, name)?;
+
+    println!(
+        "Revoked {}. A running server disconnects it straight away.",
+        peer
+    );
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use protocol::auth::rotate_static_key;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_GRACE_DAYS: u64 = 30;
+
+/// `server rotate-key [grace period in days]`: switches to a new static key, still accepting the
+/// old one until clients have picked up the new one.
+pub fn command(base: &Path, args: &[String]) -> io::Result<()> {
+    let days = match args.first() {
+        Some(days) => days.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: server rotate-key [grace period in days]",
+            )
+        })?,
+        None => DEFAULT_GRACE_DAYS,
+    };
+
+    let grace = days
+        .checked_mul(24 * 60 * 60)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Grace period too long"))?;
+    let key = rotate_static_key(base, Duration::from_secs(grace))?;
+
+    println!("New server key {}", hex::encode(key));
+    println!(
+        "The old one is accepted for {} more days. Send SIGHUP to a running server to start using the new one.",
+        days
+    );
+    Ok(())
+}
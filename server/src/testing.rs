@@ -0,0 +1,68 @@
+//! Helpers for tests that need real sessions without a real network.
+
+use crate::ByteReceiver;
+use crate::client::table::ClientTable;
+use crate::client::types::ClientInfo;
+use crate::config::DuplicatePolicy;
+use crate::net::mac::Mac;
+use protocol::auth::peer::Peer;
+use protocol::noise::session::Session;
+use snow::Builder;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+const PARAMS: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+/// A session over loopback, plus the client's end of the socket.
+pub fn session() -> (Arc<Session>, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let mut initiator = Builder::new(PARAMS.parse().unwrap())
+        .build_initiator()
+        .unwrap();
+    let mut responder = Builder::new(PARAMS.parse().unwrap())
+        .build_responder()
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let mut msg = [0u8; 1024];
+    let len = initiator.write_message(&[], &mut msg).unwrap();
+    responder.read_message(&msg[..len], &mut buf).unwrap();
+    let len = responder.write_message(&[], &mut msg).unwrap();
+    initiator.read_message(&msg[..len], &mut buf).unwrap();
+
+    let transport = responder.into_transport_mode().unwrap();
+    (Arc::new(Session::new(transport, server).unwrap()), client)
+}
+
+pub struct Connected {
+    pub ci: Arc<ClientInfo>,
+    pub replaced: Vec<Arc<ClientInfo>>,
+    /// What the TAP reader sends the client.
+    pub rx: ByteReceiver,
+    /// The client's end of the session's socket.
+    pub stream: TcpStream,
+}
+
+/// Connects a peer with a key made of `key` bytes, as `client_thread` does after its handshake.
+pub fn connect(
+    table: &ClientTable,
+    name: &str,
+    key: u8,
+    mac: Mac,
+    policy: DuplicatePolicy,
+) -> io::Result<Connected> {
+    let (session, stream) = session();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let peer = Peer::new(name, vec![key; 32]);
+    let addr = stream.local_addr()?;
+    let (ci, replaced) = table.add_new_client(peer, addr, tx, session, mac, policy)?;
+    Ok(Connected {
+        ci,
+        replaced,
+        rx,
+        stream,
+    })
+}
@@ -0,0 +1,66 @@
+/* Keeps `Auth` in step with `allowed/`, the revocation list and the trusted CAs. Any change to
+ * them reloads every peer, and clients whose peer is gone, disabled, expired or revoked are
+ * disconnected on the spot.
+ */
+
+use crate::client::reaper::drop_revoked;
+use crate::client::table::SharedClientTable;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use protocol::auth::{REVOKED_FILE, SharedAuth, TRUSTED_CAS_FILE};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+// Editors and `mv` tend to cause several events at once, so let them settle first.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+pub fn watch_allowed(auth: SharedAuth, table: SharedClientTable) -> io::Result<()> {
+    let (dir, revoked) = {
+        let auth = auth.lock().unwrap();
+        (auth.allowed_dir(), auth.revoked_file())
+    };
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_ATTRIB;
+
+    // The revocation list and trusted CAs may not exist yet, so they are watched through
+    // their directory.
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let allowed_wd = inotify.add_watch(&dir, flags)?;
+    if let Some(parent) = revoked.parent() {
+        inotify.add_watch(parent, flags)?;
+    }
+
+    thread::spawn(move || {
+        loop {
+            let events = match inotify.read_events() {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("Stopped watching {}: {}", dir.display(), e);
+                    return;
+                }
+            };
+            let relevant = events.iter().any(|event| {
+                event.wd == allowed_wd
+                    || event
+                        .name
+                        .as_deref()
+                        .is_some_and(|name| name == REVOKED_FILE || name == TRUSTED_CAS_FILE)
+            });
+            if !relevant {
+                continue;
+            }
+            thread::sleep(SETTLE_TIME);
+
+            let reloaded = auth.lock().unwrap().reload_allowed();
+            match reloaded {
+                Ok(()) => drop_revoked(&table, &auth),
+                Err(e) => eprintln!("Failed to reload allowed client keys: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
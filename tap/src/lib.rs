@@ -1,10 +1,17 @@
+mod memory;
+mod pcap;
 mod tap_impl;
 
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(target_os = "linux")]
+pub mod netns;
 
 use std::io;
-use tap_impl::TapImpl;
+
+pub use memory::MemoryTap;
+pub use pcap::PcapTap;
+pub use tap_impl::TapImpl;
 
 pub struct Tap {
     inner: Box<dyn TapImpl>,
@@ -21,7 +28,28 @@ impl Tap {
         }
 
         #[allow(unreachable_code)]
-        Err(io::Error::new(io::ErrorKind::Other, "Unsupported OS"))
+        Err(io::Error::other("Unsupported OS"))
+    }
+
+    /// Creates the device inside the named network namespace.
+    pub fn new_in_netns(name: &str, netns: &str) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            let backend = crate::linux::LinuxTap::new_in_netns(name, Some(netns))?;
+            return Ok(Self {
+                inner: Box::new(backend),
+            });
+        }
+
+        #[allow(unreachable_code)]
+        Err(io::Error::other("Unsupported OS"))
+    }
+
+    /// Wraps an arbitrary backend, e.g. a `MemoryTap` or `PcapTap` for tests.
+    pub fn from_impl(backend: impl TapImpl + 'static) -> Self {
+        Self {
+            inner: Box::new(backend),
+        }
     }
 
     pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
@@ -32,6 +60,14 @@ impl Tap {
         self.inner.write(buf)
     }
 
+    pub fn read_batch(&self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        self.inner.read_batch(bufs, lens)
+    }
+
+    pub fn write_batch(&self, frames: &[&[u8]]) -> io::Result<usize> {
+        self.inner.write_batch(frames)
+    }
+
     pub fn up(&self) -> io::Result<()> {
         self.inner.up()
     }
@@ -1,15 +1,19 @@
 #![cfg(target_os = "linux")]
 
 use crate::TapImpl;
+use crate::netns::{NetnsGuard, in_netns};
 use libc::{IFF_NO_PI, IFF_TAP};
+use nix::errno::Errno;
 use nix::fcntl::{OFlag, open};
 use nix::ioctl_write_ptr;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 use nix::sys::stat::Mode;
 use nix::unistd::dup;
 use std::ffi::{CStr, CString};
 use std::io;
 use std::mem;
 use std::os::unix::io::{BorrowedFd, RawFd};
+use std::sync::Mutex;
 
 ioctl_write_ptr!(tun_set_iff, b'T', 202, Ifreq);
 
@@ -17,6 +21,10 @@ pub struct LinuxTap {
     fd: RawFd,
     fd_write: RawFd,
     name: String,
+    netns: Option<String>,
+    // Read when the device is created and kept up to date by `set_mac`, so the read loops
+    // don't have to enter `netns` for every batch.
+    mac: Mutex<[u8; 6]>,
 }
 
 #[repr(C)]
@@ -34,9 +42,22 @@ pub struct Ifreq {
 
 impl LinuxTap {
     pub fn new(name: &str) -> io::Result<Self> {
-        // Open FD to /dev/net/tun
-        let fd = open("/dev/net/tun", OFlag::O_RDWR, Mode::empty())
-            .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+        Self::new_in_netns(name, None)
+    }
+
+    /// Creates the device inside `netns`. The fd keeps working from any
+    /// namespace, but control ioctls are issued from inside `netns`.
+    pub fn new_in_netns(name: &str, netns: Option<&str>) -> io::Result<Self> {
+        let _guard = netns.map(NetnsGuard::enter).transpose()?;
+
+        // Open FD to /dev/net/tun. It is non-blocking so `read_batch` can drain
+        // whatever is queued; `read` and `write` wait with poll instead.
+        let fd = open(
+            "/dev/net/tun",
+            OFlag::O_RDWR | OFlag::O_NONBLOCK,
+            Mode::empty(),
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
 
         // Set up ifreq C struct.
         let mut ifr: Ifreq = unsafe { mem::zeroed() };
@@ -70,31 +91,132 @@ impl LinuxTap {
 
         let fd_write = dup(fd).unwrap();
 
-        Ok(Self {
+        let tap = Self {
             fd,
             fd_write,
             name: actual,
+            netns: netns.map(|n| n.to_string()),
+            mac: Mutex::new([0; 6]),
+        };
+        *tap.mac.lock().unwrap() = tap.query_mac()?;
+        Ok(tap)
+    }
+
+    /// Asks the kernel for the device's MAC.
+    fn query_mac(&self) -> io::Result<[u8; 6]> {
+        unsafe {
+            let sock = self.control_socket()?;
+
+            let ifr = ifreq_for(&self.name);
+
+            if libc::ioctl(sock, libc::SIOCGIFHWADDR, &ifr) < 0 {
+                let e = io::Error::last_os_error();
+                libc::close(sock);
+                return Err(e);
+            }
+
+            let mut mac = [0u8; 6];
+            for (dst, b) in mac.iter_mut().zip(ifr.ifr_ifru.ifru_addr.sa_data) {
+                *dst = b as u8;
+            }
+
+            libc::close(sock);
+            Ok(mac)
+        }
+    }
+
+    /// Opens a socket for interface ioctls in the namespace the device lives in.
+    fn control_socket(&self) -> io::Result<RawFd> {
+        in_netns(self.netns.as_deref(), || {
+            let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+            if sock < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sock)
         })
     }
+
+    fn wait(fd: RawFd, events: PollFlags) -> io::Result<()> {
+        let bfd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let mut fds = [PollFd::new(bfd, events)];
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(e) => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> nix::Result<usize> {
+        nix::unistd::read(self.fd, buf)
+    }
 }
 
 impl TapImpl for LinuxTap {
     fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        nix::unistd::read(self.fd, buf).map_err(|e| io::Error::from_raw_os_error(e as i32))
+        loop {
+            match self.try_read(buf) {
+                Ok(n) => return Ok(n),
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => Self::wait(self.fd, PollFlags::POLLIN)?,
+                Err(e) => return Err(io::Error::from_raw_os_error(e as i32)),
+            }
+        }
     }
 
     fn write(&self, buf: &[u8]) -> io::Result<usize> {
         let bfd = unsafe { BorrowedFd::borrow_raw(self.fd_write) };
-        nix::unistd::write(bfd, buf).map_err(|e| io::Error::from_raw_os_error(e as i32))
+        loop {
+            match nix::unistd::write(bfd, buf) {
+                Ok(n) => return Ok(n),
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => {
+                    Self::wait(self.fd_write, PollFlags::POLLOUT)?
+                }
+                Err(e) => return Err(io::Error::from_raw_os_error(e as i32)),
+            }
+        }
+    }
+
+    fn read_batch(&self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        let max = bufs.len().min(lens.len());
+        if max == 0 {
+            return Ok(0);
+        }
+
+        // A TAP fd hands out one frame per read, so block for the first frame
+        // and then take whatever else is already queued without polling again.
+        lens[0] = self.read(bufs[0])?;
+
+        let mut count = 1;
+        while count < max {
+            match self.try_read(bufs[count]) {
+                Ok(n) => lens[count] = n,
+                Err(_) => break,
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write_batch(&self, frames: &[&[u8]]) -> io::Result<usize> {
+        // Each write(2) on a TAP fd is exactly one frame (writev would glue frames together),
+        // so a batch is written back to back and only polls once the device pushes back.
+        let bfd = unsafe { BorrowedFd::borrow_raw(self.fd_write) };
+        let mut written = 0;
+        while written < frames.len() {
+            match nix::unistd::write(bfd, frames[written]) {
+                Ok(_) => written += 1,
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => {
+                    Self::wait(self.fd_write, PollFlags::POLLOUT)?
+                }
+                Err(e) => return Err(io::Error::from_raw_os_error(e as i32)),
+            }
+        }
+        Ok(written)
     }
 
     fn up(&self) -> io::Result<()> {
         unsafe {
             // Open a control socket.
-            let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
-            if sock < 0 {
-                return Err(io::Error::last_os_error());
-            }
+            let sock = self.control_socket()?;
 
             let mut ifr = ifreq_for(&self.name);
 
@@ -122,10 +244,7 @@ impl TapImpl for LinuxTap {
 
     fn set_mtu(&self, mtu: i32) -> io::Result<()> {
         unsafe {
-            let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
-            if sock < 0 {
-                return Err(io::Error::last_os_error());
-            }
+            let sock = self.control_socket()?;
 
             let mut ifr = ifreq_for(&self.name);
 
@@ -142,56 +261,33 @@ impl TapImpl for LinuxTap {
 
     fn set_mac(&self, mac: [u8; 6]) -> io::Result<()> {
         unsafe {
-            let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
-            if sock < 0 {
-                return Err(io::Error::last_os_error());
-            }
+            let sock = self.control_socket()?;
 
             let mut ifr = ifreq_for(&self.name);
 
             let mut addr: libc::sockaddr = std::mem::zeroed();
             addr.sa_family = libc::ARPHRD_ETHER as libc::sa_family_t;
 
-            for i in 0..6 {
-                addr.sa_data[i] = mac[i] as libc::c_char;
+            for (dst, b) in addr.sa_data.iter_mut().zip(mac) {
+                *dst = b as libc::c_char;
             }
 
             ifr.ifr_ifru.ifru_addr = addr;
 
             if libc::ioctl(sock, libc::SIOCSIFHWADDR, &ifr) < 0 {
-                return Err(io::Error::last_os_error());
+                let e = io::Error::last_os_error();
+                libc::close(sock);
+                return Err(e);
             }
 
             libc::close(sock);
+            *self.mac.lock().unwrap() = mac;
             Ok(())
         }
     }
 
     fn get_mac(&self) -> io::Result<[u8; 6]> {
-        unsafe {
-            let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
-            if sock < 0 {
-                return Err(io::Error::last_os_error());
-            }
-
-            let mut ifr = ifreq_for(&self.name);
-
-            for (i, b) in self.ifname().bytes().enumerate() {
-                ifr.ifr_name[i] = b as libc::c_char;
-            }
-
-            if libc::ioctl(sock, libc::SIOCGIFHWADDR, &ifr) < 0 {
-                return Err(io::Error::last_os_error());
-            }
-
-            let mut mac = [0u8; 6];
-            for i in 0..6 {
-                mac[i] = ifr.ifr_ifru.ifru_addr.sa_data[i] as u8;
-            }
-
-            libc::close(sock);
-            Ok(mac)
-        }
+        Ok(*self.mac.lock().unwrap())
     }
 
     fn ifname(&self) -> &str {
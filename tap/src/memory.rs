@@ -0,0 +1,142 @@
+use crate::TapImpl;
+use crossbeam_channel::{Receiver, Sender};
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+const DEFAULT_MTU: i32 = 1500;
+
+/// An in-memory TAP backend. Frames written to one end of a pair are read
+/// from the other, so no root or `/dev/net/tun` is required.
+pub struct MemoryTap {
+    name: String,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    mac: Mutex<[u8; 6]>,
+    mtu: AtomicI32,
+    up: AtomicBool,
+}
+
+impl MemoryTap {
+    /// Creates two connected endpoints, like a veth pair.
+    pub fn pair(name_a: &str, name_b: &str) -> (Self, Self) {
+        let (a_tx, b_rx) = crossbeam_channel::unbounded();
+        let (b_tx, a_rx) = crossbeam_channel::unbounded();
+
+        let a = Self::new(name_a, a_tx, a_rx, [0x02, 0, 0, 0, 0, 0x01]);
+        let b = Self::new(name_b, b_tx, b_rx, [0x02, 0, 0, 0, 0, 0x02]);
+        (a, b)
+    }
+
+    fn new(name: &str, tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>, mac: [u8; 6]) -> Self {
+        Self {
+            name: name.to_string(),
+            tx,
+            rx,
+            mac: Mutex::new(mac),
+            mtu: AtomicI32::new(DEFAULT_MTU),
+            up: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn mtu(&self) -> i32 {
+        self.mtu.load(Ordering::Relaxed)
+    }
+}
+
+impl TapImpl for MemoryTap {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let frame = self
+            .rx
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Peer endpoint closed"))?;
+
+        Ok(copy_frame(&frame, buf))
+    }
+
+    fn read_batch(&self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        let max = bufs.len().min(lens.len());
+        if max == 0 {
+            return Ok(0);
+        }
+
+        lens[0] = self.read(bufs[0])?;
+
+        let mut count = 1;
+        while count < max {
+            let Ok(frame) = self.rx.try_recv() else {
+                break;
+            };
+            lens[count] = copy_frame(&frame, bufs[count]);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Peer endpoint closed"))?;
+        Ok(buf.len())
+    }
+
+    fn up(&self) -> io::Result<()> {
+        self.up.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_mtu(&self, mtu: i32) -> io::Result<()> {
+        if mtu <= 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid MTU"));
+        }
+        self.mtu.store(mtu, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_mac(&self, mac: [u8; 6]) -> io::Result<()> {
+        *self.mac.lock().unwrap() = mac;
+        Ok(())
+    }
+
+    fn get_mac(&self) -> io::Result<[u8; 6]> {
+        Ok(*self.mac.lock().unwrap())
+    }
+
+    fn ifname(&self) -> &str {
+        &self.name
+    }
+}
+
+fn copy_frame(frame: &[u8], buf: &mut [u8]) -> usize {
+    // Like a datagram read, anything that doesn't fit is dropped.
+    let n = frame.len().min(buf.len());
+    buf[..n].copy_from_slice(&frame[..n]);
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_batch_stops_at_the_shorter_of_bufs_and_lens() {
+        let (a, b) = MemoryTap::pair("a", "b");
+        let frames: [&[u8]; 3] = [b"one", b"two", b"three"];
+        a.write_batch(&frames).unwrap();
+
+        let mut storage = [[0u8; 16]; 3];
+        let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|b| &mut b[..]).collect();
+        let mut lens = [0usize; 2];
+        assert_eq!(b.read_batch(&mut bufs, &mut lens).unwrap(), 2);
+        assert_eq!(&bufs[0][..lens[0]], b"one");
+        assert_eq!(&bufs[1][..lens[1]], b"two");
+
+        assert_eq!(b.read_batch(&mut bufs, &mut lens).unwrap(), 1);
+        assert_eq!(&bufs[0][..lens[0]], b"three");
+    }
+}
@@ -0,0 +1,50 @@
+#![cfg(target_os = "linux")]
+
+use nix::sched::{CloneFlags, setns};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+/// Switches the calling thread into a network namespace, restoring the
+/// original namespace when dropped. Anything created while the guard is
+/// alive (TAP devices, sockets, child processes) stays in that namespace.
+pub struct NetnsGuard {
+    original: File,
+}
+
+impl NetnsGuard {
+    /// Enters a namespace by name (as created by `ip netns add`) or by path.
+    pub fn enter(netns: &str) -> io::Result<Self> {
+        let original = File::open("/proc/thread-self/ns/net")?;
+        let target = File::open(netns_path(netns))?;
+
+        setns(&target, CloneFlags::CLONE_NEWNET)
+            .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+
+        Ok(Self { original })
+    }
+}
+
+impl Drop for NetnsGuard {
+    fn drop(&mut self) {
+        if let Err(e) = setns(&self.original, CloneFlags::CLONE_NEWNET) {
+            eprintln!("Failed to restore network namespace: {}", e);
+        }
+    }
+}
+
+/// Runs `f` inside `netns`, or in the current namespace if none is given.
+pub fn in_netns<T>(netns: Option<&str>, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let _guard = netns.map(NetnsGuard::enter).transpose()?;
+    f()
+}
+
+fn netns_path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        PathBuf::from(netns)
+    } else {
+        PathBuf::from(NETNS_RUN_DIR).join(netns)
+    }
+}
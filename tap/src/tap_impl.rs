@@ -8,4 +8,25 @@ pub trait TapImpl: Send + Sync {
     fn set_mac(&self, mac: [u8; 6]) -> io::Result<()>;
     fn get_mac(&self) -> io::Result<[u8; 6]>;
     fn ifname(&self) -> &str;
+
+    /// Reads up to as many frames as there are `bufs` and `lens`, blocking
+    /// only until the first one arrives. The length of each frame is stored
+    /// in `lens`.
+    fn read_batch(&self, bufs: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        match bufs.first_mut() {
+            Some(buf) if !lens.is_empty() => {
+                lens[0] = self.read(buf)?;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Writes every frame in order, returning how many were written.
+    fn write_batch(&self, frames: &[&[u8]]) -> io::Result<usize> {
+        for frame in frames {
+            self.write(frame)?;
+        }
+        Ok(frames.len())
+    }
 }